@@ -0,0 +1,180 @@
+use anyhow::{ensure, Error, Result};
+
+use crate::image::{ColorType, RawImage};
+
+/// Size of the payload length that is written before the payload
+pub const HEADER_SIZE: usize = 4;
+
+/// Size in bytes of a sample and of a pixel
+fn layout(image: &RawImage) -> Result<(usize, usize), Error> {
+    ensure!(
+        matches!(
+            image.color_type,
+            ColorType::GrayscaleAlpha | ColorType::Rgba
+        ),
+        "Only images with an alpha channel have transparent pixels"
+    );
+    ensure!(
+        image.bit_depth >= 8,
+        "Only 8 and 16 bit images are supported"
+    );
+
+    let size = image.bit_depth as usize / 8;
+    Ok((size, size * image.color_type.channels()))
+}
+
+/// Whether every byte of the alpha sample at the end of the pixel is zero
+fn transparent(pixel: &[u8], size: usize) -> bool {
+    pixel[pixel.len() - size..].iter().all(|&byte| byte == 0)
+}
+
+/// Ranges of the color sample bytes of every fully transparent pixel, in raster order
+///
+/// Embedding never touches alpha, so the same pixels are found again when extracting.
+fn color_ranges(image: &RawImage) -> Result<Vec<(usize, usize)>, Error> {
+    let (size, pixel_size) = layout(image)?;
+
+    Ok(image
+        .data
+        .chunks(pixel_size)
+        .enumerate()
+        .filter(|(_, pixel)| transparent(pixel, size))
+        .map(|(i, _)| (i * pixel_size, i * pixel_size + pixel_size - size))
+        .collect())
+}
+
+/// Color sample bytes of the fully transparent pixels
+fn color_bytes(image: &RawImage) -> Result<Vec<usize>, Error> {
+    Ok(color_ranges(image)?
+        .into_iter()
+        .flat_map(|(start, end)| start..end)
+        .collect())
+}
+
+/// Maximum payload size in bytes that fits in the colors of fully transparent pixels
+pub fn capacity(image: &RawImage) -> Result<usize, Error> {
+    let (size, pixel_size) = layout(image)?;
+    let pixels = image
+        .data
+        .chunks(pixel_size)
+        .filter(|pixel| transparent(pixel, size))
+        .count();
+
+    Ok((pixels * (pixel_size - size)).saturating_sub(HEADER_SIZE))
+}
+
+/// Overwrites the color samples of fully transparent pixels with the payload and its length
+pub fn embed(image: &mut RawImage, payload: &[u8]) -> Result<(), Error> {
+    let bytes = color_bytes(image)?;
+    let capacity = bytes.len().saturating_sub(HEADER_SIZE);
+    ensure!(
+        payload.len() <= capacity,
+        "Payload is too long, the transparent pixels can hold at most {} bytes",
+        capacity
+    );
+
+    (payload.len() as u32)
+        .to_be_bytes()
+        .iter()
+        .chain(payload)
+        .zip(bytes)
+        .for_each(|(&byte, i)| image.data[i] = byte);
+
+    Ok(())
+}
+
+/// Reads a payload written by [`embed`]
+pub fn extract(image: &RawImage) -> Result<Vec<u8>, Error> {
+    let bytes: Vec<u8> = color_bytes(image)?
+        .into_iter()
+        .map(|i| image.data[i])
+        .collect();
+    ensure!(
+        bytes.len() >= HEADER_SIZE,
+        "Not enough transparent pixels for a message"
+    );
+
+    let len = u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as usize;
+    ensure!(
+        len <= bytes.len() - HEADER_SIZE,
+        "Transparent pixels do not hold a message"
+    );
+
+    Ok(bytes[HEADER_SIZE..HEADER_SIZE + len].to_vec())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Image whose odd pixels are fully transparent
+    fn image(bit_depth: u8, color_type: ColorType) -> RawImage {
+        let size = bit_depth as usize / 8;
+        let pixel_size = size * color_type.channels();
+        let data = (0..64)
+            .flat_map(|i| {
+                let mut pixel = vec![i as u8 | 1; pixel_size];
+                if i % 2 == 1 {
+                    pixel[pixel_size - size..].fill(0);
+                }
+                pixel
+            })
+            .collect();
+
+        RawImage {
+            width: 8,
+            height: 8,
+            bit_depth,
+            color_type,
+            data,
+        }
+    }
+
+    #[test]
+    fn test_embed_extract() {
+        let mut image = image(8, ColorType::Rgba);
+        let original = image.clone();
+        let payload = b"Hidden in plain sight";
+
+        embed(&mut image, payload).unwrap();
+        assert_eq!(extract(&image).unwrap(), payload);
+
+        // Opaque pixels and alpha values are untouched
+        for (pixel, before) in image.data.chunks(4).zip(original.data.chunks(4)) {
+            if before[3] != 0 {
+                assert_eq!(pixel, before);
+            } else {
+                assert_eq!(pixel[3], 0);
+            }
+        }
+    }
+
+    #[test]
+    fn test_embed_extract_16_bit_gray() {
+        let mut image = image(16, ColorType::GrayscaleAlpha);
+        let payload = vec![7; capacity(&image).unwrap()];
+
+        embed(&mut image, &payload).unwrap();
+        assert_eq!(extract(&image).unwrap(), payload);
+    }
+
+    #[test]
+    fn test_capacity() {
+        assert_eq!(
+            capacity(&image(8, ColorType::Rgba)).unwrap(),
+            32 * 3 - HEADER_SIZE
+        );
+        assert_eq!(
+            capacity(&image(16, ColorType::Rgba)).unwrap(),
+            32 * 6 - HEADER_SIZE
+        );
+        assert!(capacity(&image(8, ColorType::Rgb)).is_err());
+    }
+
+    #[test]
+    fn test_embed_too_long() {
+        let mut image = image(8, ColorType::Rgba);
+        let payload = vec![1; capacity(&image).unwrap() + 1];
+        assert!(embed(&mut image, &payload).is_err());
+    }
+}
@@ -0,0 +1,158 @@
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+
+use anyhow::{bail, ensure, Context, Error, Result};
+use tar::{Archive, Builder, EntryType};
+
+/// Packs the files under a directory into a tar stream, with paths relative to it
+///
+/// Fails on links, which [`unpack`] refuses.
+pub fn pack(dir: &Path) -> Result<Vec<u8>, Error> {
+    ensure!(dir.is_dir(), "{} is not a directory", dir.display());
+
+    // Links are packed as links, which unpack refuses, instead of the files they point to
+    let mut builder = Builder::new(Vec::new());
+    builder.follow_symlinks(false);
+    builder
+        .append_dir_all(".", dir)
+        .with_context(|| format!("Cannot pack {}", dir.display()))?;
+    let data = builder.into_inner()?;
+    entries(&data)?;
+
+    Ok(data)
+}
+
+/// Checks that a path stays inside the directory it is unpacked into
+fn check_path(path: &Path) -> Result<(), Error> {
+    for component in path.components() {
+        match component {
+            Component::Normal(_) | Component::CurDir => {}
+            _ => bail!("Archive entry {} leaves its directory", path.display()),
+        }
+    }
+
+    Ok(())
+}
+
+/// Paths and sizes of the entries, failing on entries that [`unpack`] would refuse
+pub fn entries(data: &[u8]) -> Result<Vec<(PathBuf, u64)>, Error> {
+    let mut archive = Archive::new(data);
+    let mut entries = Vec::new();
+    for entry in archive.entries()? {
+        let entry = entry?;
+        let path = entry.path()?.into_owned();
+        check_path(&path)?;
+        // The directory itself, packed as ./
+        if path
+            .components()
+            .all(|component| component == Component::CurDir)
+        {
+            continue;
+        }
+        ensure!(
+            matches!(
+                entry.header().entry_type(),
+                EntryType::Regular | EntryType::Directory
+            ),
+            "Archive entry {} is not a file or a directory",
+            path.display()
+        );
+        entries.push((path, entry.size()));
+    }
+
+    Ok(entries)
+}
+
+/// Unpacks the tar stream into a directory, only after every entry is checked
+///
+/// Returns the number of entries.
+pub fn unpack(data: &[u8], dir: &Path) -> Result<usize, Error> {
+    let count = entries(data)?.len();
+    fs::create_dir_all(dir)?;
+
+    let mut archive = Archive::new(data);
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let path = entry.path()?.into_owned();
+        ensure!(
+            entry.unpack_in(dir)?,
+            "Archive entry {} leaves its directory",
+            path.display()
+        );
+    }
+
+    Ok(count)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("pngme-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    #[test]
+    fn test_pack_unpack() {
+        let dir = temp_dir("pack");
+        fs::create_dir_all(dir.join("config")).unwrap();
+        fs::write(dir.join("README"), "Read me").unwrap();
+        fs::write(dir.join("config").join("app.toml"), "key = 1").unwrap();
+
+        let data = pack(&dir).unwrap();
+        let mut paths: Vec<(PathBuf, u64)> = entries(&data)
+            .unwrap()
+            .into_iter()
+            .filter(|(_, size)| *size > 0)
+            .collect();
+        paths.sort();
+        assert_eq!(
+            paths,
+            [
+                (PathBuf::from("README"), 7),
+                (PathBuf::from("config/app.toml"), 7)
+            ]
+        );
+
+        let out = temp_dir("unpack");
+        unpack(&data, &out).unwrap();
+        assert_eq!(
+            fs::read_to_string(out.join("config").join("app.toml")).unwrap(),
+            "key = 1"
+        );
+
+        fs::remove_dir_all(dir).unwrap();
+        fs::remove_dir_all(out).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_symlink_not_followed() {
+        let dir = temp_dir("symlink");
+        fs::create_dir_all(&dir).unwrap();
+        std::os::unix::fs::symlink("/etc/hostname", dir.join("link")).unwrap();
+
+        assert!(pack(&dir).is_err());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_path_traversal() {
+        for path in ["../escape", "/etc/escape", "dir/../../escape"] {
+            let mut header = tar::Header::new_gnu();
+            // set_path refuses these paths, so the name is written directly
+            header.as_old_mut().name[..path.len()].copy_from_slice(path.as_bytes());
+            header.set_size(4);
+            header.set_cksum();
+            let mut builder = Builder::new(Vec::new());
+            builder.append(&header, &b"evil"[..]).unwrap();
+            let data = builder.into_inner().unwrap();
+
+            assert!(entries(&data).is_err());
+            assert!(unpack(&data, &temp_dir("traversal")).is_err());
+        }
+    }
+}
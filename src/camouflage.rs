@@ -0,0 +1,348 @@
+use std::str::FromStr;
+
+use anyhow::{anyhow, bail, ensure, Context, Error, Result};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use clap::ValueEnum;
+
+use crate::chunk::Chunk;
+use crate::chunk_type::ChunkType;
+use crate::png::Png;
+
+const XMP_KEYWORD: &str = "XML:com.adobe.xmp";
+
+const COMMENT_KEYWORD: &str = "Comment";
+
+/// Base64 lines are broken like in the thumbnails Adobe applications write
+const XMP_LINE_LENGTH: usize = 76;
+
+/// Every byte is written as an adjective for its high and a noun for its low four bits
+const ADJECTIVES: [&str; 16] = [
+    "quiet", "bright", "old", "warm", "small", "green", "late", "soft", "open", "dark", "calm",
+    "long", "wild", "cold", "young", "clear",
+];
+const NOUNS: [&str; 16] = [
+    "river", "stone", "garden", "light", "road", "window", "morning", "field", "house", "bird",
+    "summer", "hill", "letter", "tree", "harbor", "song",
+];
+
+/// Number of word pairs in every sentence of a comment, cycled through
+const SENTENCE_PAIRS: [usize; 5] = [3, 5, 2, 4, 6];
+
+/// Standard chunk that a payload is disguised as
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum Style {
+    /// Base64 thumbnail of an XMP packet in an iTXt chunk
+    Xmp,
+    /// Text of a tEXt chunk with the Comment keyword, one word pair per byte
+    Comment,
+}
+
+/// Wraps the payload in a chunk of the given style
+fn chunk(payload: &[u8], style: Style) -> Chunk {
+    match style {
+        Style::Xmp => xmp_chunk(&xmp_packet(payload)),
+        Style::Comment => Chunk::new(
+            ChunkType::from_str("tEXt").unwrap(),
+            [
+                COMMENT_KEYWORD.as_bytes(),
+                b"\0",
+                comment_text(payload).as_bytes(),
+            ]
+            .concat(),
+        ),
+    }
+}
+
+fn xmp_chunk(packet: &str) -> Chunk {
+    Chunk::new(
+        ChunkType::from_str("iTXt").unwrap(),
+        // Uncompressed, with empty language tag and translated keyword
+        [XMP_KEYWORD.as_bytes(), b"\0\0\0\0\0", packet.as_bytes()].concat(),
+    )
+}
+
+/// Hides the payload in a chunk of the given style
+///
+/// An image holds at most one XMP packet, so the payload is merged into a packet the image
+/// already has, replacing its thumbnails, or replaces a packet it cannot be merged into.
+pub fn embed(png: &mut Png, payload: &[u8], style: Style) {
+    let existing = png
+        .chunks
+        .iter()
+        .position(|chunk| keyword_text(chunk, "iTXt", XMP_KEYWORD).is_some());
+
+    match (style, existing) {
+        (Style::Xmp, Some(i)) => {
+            let merged = xmp_text(&png.chunks[i])
+                .and_then(|text| std::str::from_utf8(text).ok())
+                .and_then(|packet| merge_xmp_packet(packet, payload));
+            png.chunks[i] =
+                merged.map_or_else(|| chunk(payload, style), |packet| xmp_chunk(&packet));
+        }
+        _ => png.append_chunk(chunk(payload, style)),
+    }
+}
+
+/// Largest payload that fits into one chunk of the given style
+pub fn capacity(style: Style) -> usize {
+    let room = Png::MAX_CHUNK_LENGTH - chunk(&[], style).data.len();
+    match style {
+        // Every line of 76 base64 characters holds 57 bytes and is followed by "&#xA;"
+        Style::Xmp => room / (XMP_LINE_LENGTH + 5) * (XMP_LINE_LENGTH / 4 * 3),
+        // Longest word pair followed by ". ", the widest separator
+        Style::Comment => room / ("bright morning. ".len()),
+    }
+}
+
+/// Text of a chunk of the given type after its keyword
+fn keyword_text<'a>(chunk: &'a Chunk, chunk_type: &str, keyword: &str) -> Option<&'a [u8]> {
+    if chunk.r#type.to_string() != chunk_type {
+        return None;
+    }
+
+    chunk
+        .data
+        .strip_prefix(keyword.as_bytes())?
+        .strip_prefix(b"\0")
+}
+
+/// Packet of an uncompressed iTXt chunk with the XMP keyword
+fn xmp_text(chunk: &Chunk) -> Option<&[u8]> {
+    let ([compressed, _], rest) = keyword_text(chunk, "iTXt", XMP_KEYWORD)?.split_first_chunk()?;
+    if *compressed != 0 {
+        return None;
+    }
+
+    // Language tag and translated keyword, both ending with a null byte
+    let rest = &rest[rest.iter().position(|&byte| byte == 0)? + 1..];
+    Some(&rest[rest.iter().position(|&byte| byte == 0)? + 1..])
+}
+
+/// Finds a disguised payload, in every XMP packet first and in every comment next
+pub fn extract(png: &Png) -> Result<Vec<u8>, Error> {
+    let packets = png
+        .chunks
+        .iter()
+        .filter_map(xmp_text)
+        .filter_map(|text| read_xmp_packet(std::str::from_utf8(text).ok()?).ok());
+    let comments = png
+        .chunks
+        .iter()
+        .filter_map(|chunk| keyword_text(chunk, "tEXt", COMMENT_KEYWORD))
+        .filter_map(|text| read_comment_text(std::str::from_utf8(text).ok()?).ok());
+
+    packets
+        .chain(comments)
+        .next()
+        .context("No XMP packet or comment holds a message")
+}
+
+/// Description with the payload as a thumbnail, declaring the namespaces it uses
+fn xmp_description(payload: &[u8]) -> String {
+    let image = STANDARD
+        .encode(payload)
+        .as_bytes()
+        .chunks(XMP_LINE_LENGTH)
+        .map(|line| std::str::from_utf8(line).unwrap())
+        .collect::<Vec<&str>>()
+        .join("&#xA;");
+
+    format!(
+        r#"  <rdf:Description rdf:about=""
+    xmlns:xmp="http://ns.adobe.com/xap/1.0/"
+    xmlns:xmpGImg="http://ns.adobe.com/xap/1.0/g/img/">
+   <xmp:Thumbnails>
+    <rdf:Alt>
+     <rdf:li rdf:parseType="Resource">
+      <xmpGImg:format>JPEG</xmpGImg:format>
+      <xmpGImg:width>256</xmpGImg:width>
+      <xmpGImg:height>256</xmpGImg:height>
+      <xmpGImg:image>{}</xmpGImg:image>
+     </rdf:li>
+    </rdf:Alt>
+   </xmp:Thumbnails>
+  </rdf:Description>
+"#,
+        image
+    )
+}
+
+fn xmp_packet(payload: &[u8]) -> String {
+    format!(
+        r#"<?xpacket begin="" id="W5M0MpCehiHzreSzNTczkc9d"?>
+<x:xmpmeta xmlns:x="adobe:ns:meta/" x:xmptk="Adobe XMP Core 7.1-c000 79.dabacbb, 2021/04/14-00:39:44">
+ <rdf:RDF xmlns:rdf="http://www.w3.org/1999/02/22-rdf-syntax-ns#">
+  <rdf:Description rdf:about=""
+    xmlns:xmp="http://ns.adobe.com/xap/1.0/"
+   xmp:CreatorTool="Adobe Photoshop 22.4 (Windows)"/>
+{} </rdf:RDF>
+</x:xmpmeta>
+<?xpacket end="w"?>"#,
+        xmp_description(payload)
+    )
+}
+
+/// Adds the payload to an existing packet as another description, dropping its thumbnails
+fn merge_xmp_packet(packet: &str, payload: &[u8]) -> Option<String> {
+    const THUMBNAILS: (&str, &str) = ("<xmp:Thumbnails>", "</xmp:Thumbnails>");
+
+    let packet = match (packet.find(THUMBNAILS.0), packet.find(THUMBNAILS.1)) {
+        (Some(start), Some(end)) if start < end => format!(
+            "{}{}",
+            &packet[..start],
+            &packet[end + THUMBNAILS.1.len()..]
+        ),
+        _ => packet.to_string(),
+    };
+    let end = packet
+        .find(" </rdf:RDF>")
+        .or_else(|| packet.find("</rdf:RDF>"))?;
+
+    Some(format!(
+        "{}{}{}",
+        &packet[..end],
+        xmp_description(payload),
+        &packet[end..]
+    ))
+}
+
+fn read_xmp_packet(packet: &str) -> Result<Vec<u8>, Error> {
+    let start = packet
+        .find("<xmpGImg:image>")
+        .context("XMP packet holds no thumbnail")?
+        + "<xmpGImg:image>".len();
+    let end = start
+        + packet[start..]
+            .find("</xmpGImg:image>")
+            .context("XMP thumbnail is not closed")?;
+
+    STANDARD
+        .decode(packet[start..end].replace("&#xA;", ""))
+        .map_err(|error| anyhow!("XMP thumbnail is not base64: {}", error))
+}
+
+fn comment_text(payload: &[u8]) -> String {
+    let mut pairs = payload.iter().map(|&byte| {
+        format!(
+            "{} {}",
+            ADJECTIVES[byte as usize >> 4],
+            NOUNS[byte as usize & 15]
+        )
+    });
+    let mut sentences = Vec::new();
+
+    for &len in SENTENCE_PAIRS.iter().cycle() {
+        let sentence: Vec<String> = pairs.by_ref().take(len).collect();
+        if sentence.is_empty() {
+            break;
+        }
+        let sentence = sentence.join(" ");
+        sentences.push(format!(
+            "{}{}.",
+            sentence[..1].to_uppercase(),
+            &sentence[1..]
+        ));
+    }
+
+    sentences.join(" ")
+}
+
+fn read_comment_text(text: &str) -> Result<Vec<u8>, Error> {
+    let words: Vec<String> = text
+        .split_whitespace()
+        .map(|word| word.trim_end_matches('.').to_lowercase())
+        .collect();
+    ensure!(
+        words.len().is_multiple_of(2),
+        "Comment has an odd number of words"
+    );
+
+    words
+        .chunks(2)
+        .map(|pair| {
+            let high = ADJECTIVES.iter().position(|&word| word == pair[0]);
+            let low = NOUNS.iter().position(|&word| word == pair[1]);
+            match (high, low) {
+                (Some(high), Some(low)) => Ok((high << 4 | low) as u8),
+                _ => bail!("Comment does not hold a message"),
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::png::tests::PNG_FILE;
+
+    use super::*;
+
+    fn payload() -> Vec<u8> {
+        (0..=255).chain(0..100).map(|i| i as u8).collect()
+    }
+
+    #[test]
+    fn test_xmp() {
+        let mut png = Png::try_from(&PNG_FILE[..]).unwrap();
+        embed(&mut png, &payload(), Style::Xmp);
+
+        let png = Png::try_from(png.as_bytes().as_slice()).unwrap();
+        let chunk = png.chunk_by_type("iTXt").unwrap();
+        assert!(chunk.data.starts_with(b"XML:com.adobe.xmp\0"));
+        assert_eq!(extract(&png).unwrap(), payload());
+    }
+
+    #[test]
+    fn test_comment() {
+        let mut png = Png::try_from(&PNG_FILE[..]).unwrap();
+        embed(&mut png, &payload(), Style::Comment);
+
+        let text = &png.chunk_by_type("tEXt").unwrap().data;
+        assert!(text.starts_with(b"Comment\0Quiet river quiet stone quiet garden. "));
+        assert!(text
+            .iter()
+            .all(|&byte| byte == 0 || (32..127).contains(&byte)));
+        assert_eq!(extract(&png).unwrap(), payload());
+    }
+
+    #[test]
+    fn test_existing_xmp_packet() {
+        let mut png = Png::try_from(&PNG_FILE[..]).unwrap();
+        let existing = r#"<x:xmpmeta xmlns:x="adobe:ns:meta/"><rdf:RDF xmlns:rdf="http://www.w3.org/1999/02/22-rdf-syntax-ns#"><rdf:Description rdf:about="" xmlns:dc="http://purl.org/dc/elements/1.1/"><dc:format>image/png</dc:format></rdf:Description></rdf:RDF></x:xmpmeta>"#;
+        png.append_chunk(xmp_chunk(existing));
+        // A comment that does not hold a message comes after the packet without a thumbnail
+        png.append_chunk(chunk(&payload(), Style::Comment));
+        assert_eq!(extract(&png).unwrap(), payload());
+
+        embed(&mut png, b"merged", Style::Xmp);
+        let packets: Vec<&Chunk> = png
+            .chunks
+            .iter()
+            .filter(|chunk| xmp_text(chunk).is_some())
+            .collect();
+        assert_eq!(packets.len(), 1);
+        assert!(std::str::from_utf8(xmp_text(packets[0]).unwrap())
+            .unwrap()
+            .contains("<dc:format>image/png</dc:format>"));
+        assert_eq!(extract(&png).unwrap(), b"merged");
+
+        // Thumbnails of an earlier payload are replaced
+        embed(&mut png, b"again", Style::Xmp);
+        assert_eq!(extract(&png).unwrap(), b"again");
+        let packet = xmp_text(png.chunk_by_type("iTXt").unwrap()).unwrap();
+        assert_eq!(
+            std::str::from_utf8(packet)
+                .unwrap()
+                .matches("<xmp:Thumbnails>")
+                .count(),
+            1
+        );
+    }
+
+    #[test]
+    fn test_not_camouflaged() {
+        let png = Png::try_from(&PNG_FILE[..]).unwrap();
+        assert!(extract(&png).is_err());
+        assert!(read_comment_text("Just an ordinary comment.").is_err());
+    }
+}
@@ -0,0 +1,195 @@
+use std::fmt;
+use std::fmt::{Display, Formatter};
+
+use anyhow::{Error, Result};
+
+use crate::alpha_zero;
+use crate::camouflage;
+use crate::camouflage::Style;
+use crate::compress;
+use crate::crypto;
+use crate::decoy;
+use crate::ecc;
+use crate::envelope;
+use crate::image::Ihdr;
+use crate::lsb;
+use crate::lsb::LsbOptions;
+use crate::palette;
+use crate::png::Png;
+use crate::recipient;
+
+/// Maximum payload size of every hiding method for an image
+///
+/// Capacities are counted from the samples and chunks, nothing is laid out for embedding.
+pub struct Report {
+    ihdr: Ihdr,
+    rows: Vec<Row>,
+    /// Fixed number of bytes every method adds to the payload, with what adds them
+    overheads: Vec<(&'static str, usize)>,
+}
+
+/// Bytes of a message that fit into room for a payload of `bytes`, every method hides the
+/// message in an envelope
+fn message_capacity(bytes: usize) -> usize {
+    bytes.saturating_sub(envelope::HEADER_SIZE)
+}
+
+struct Row {
+    method: &'static str,
+    capacity: Result<usize, Error>,
+    note: String,
+}
+
+impl Report {
+    pub fn new(png: &Png, lsb_options: &LsbOptions) -> Result<Report, Error> {
+        let ihdr = png.ihdr()?;
+        let image = png.raw_image()?;
+
+        let rows = vec![
+            Row {
+                method: "chunk",
+                capacity: Ok(message_capacity(Png::MAX_CHUNK_LENGTH)),
+                note: String::from("per chunk, every byte grows the file"),
+            },
+            Row {
+                method: "lsb",
+                capacity: lsb::capacity(&image, lsb_options).map(message_capacity),
+                note: format!(
+                    "{} bits per sample, channels {}, alpha {}",
+                    lsb_options.bits, lsb_options.channels, lsb_options.alpha
+                ),
+            },
+            Row {
+                method: "palette-order",
+                capacity: palette::capacity(png).map(message_capacity),
+                note: String::from("log2(n!) bits for n palette entries"),
+            },
+            Row {
+                method: "trailer",
+                capacity: Ok(usize::MAX),
+                note: String::from("after IEND, every byte grows the file"),
+            },
+            Row {
+                method: "alpha-zero",
+                capacity: alpha_zero::capacity(&image).map(message_capacity),
+                note: String::from("whole color samples of fully transparent pixels"),
+            },
+            Row {
+                method: "camouflage",
+                capacity: Ok(message_capacity(camouflage::capacity(Style::Xmp))),
+                note: format!(
+                    "xmp style, {} bytes with the comment style",
+                    message_capacity(camouflage::capacity(Style::Comment))
+                ),
+            },
+        ];
+
+        Ok(Report {
+            ihdr,
+            rows,
+            overheads: vec![
+                ("envelope header", envelope::HEADER_SIZE),
+                ("lsb header", lsb::HEADER_SIZE),
+                ("palette header", palette::HEADER_SIZE),
+                ("alpha-zero header", alpha_zero::HEADER_SIZE),
+            ],
+        })
+    }
+}
+
+impl Display for Report {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "{}x{} {} image, {} bits per sample",
+            self.ihdr.width, self.ihdr.height, self.ihdr.color_type, self.ihdr.bit_depth
+        )?;
+
+        for row in &self.rows {
+            match &row.capacity {
+                Ok(usize::MAX) => writeln!(
+                    f,
+                    "{:<14}{:>12}        {}",
+                    row.method, "unlimited", row.note
+                )?,
+                Ok(bytes) => writeln!(f, "{:<14}{:>12} bytes  {}", row.method, bytes, row.note)?,
+                Err(error) => writeln!(f, "{:<14}{:>12}        {}", row.method, "-", error)?,
+            }
+        }
+
+        writeln!(f, "Overhead (already subtracted above):")?;
+        for (name, bytes) in &self.overheads {
+            writeln!(f, "  {:<18}{:>6} bytes", name, bytes)?;
+        }
+        writeln!(
+            f,
+            "With --ecc N, add {} bytes and N parity bytes per {} byte block",
+            ecc::OVERHEAD,
+            ecc::BLOCK_SIZE
+        )?;
+        writeln!(
+            f,
+            "With --compress, add {} bytes to the compressed message",
+            compress::HEADER_SIZE
+        )?;
+        writeln!(
+            f,
+            "With --input or --input-dir, add {} bytes and the file name and MIME type",
+            envelope::METADATA_SIZE
+        )?;
+        writeln!(f, "With --encrypt, add {} bytes", crypto::OVERHEAD)?;
+        writeln!(
+            f,
+            "With --recipient, add {} bytes and {} bytes per recipient",
+            recipient::OVERHEAD,
+            recipient::STANZA_SIZE
+        )?;
+        writeln!(
+            f,
+            "With --decoy, messages fill {} slots the size of the longest one plus {} bytes",
+            decoy::SLOTS,
+            decoy::SLOT_OVERHEAD
+        )?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::envelope::Envelope;
+    use crate::png::tests::PNG_FILE;
+
+    use super::*;
+
+    #[test]
+    fn test_report_png_file() {
+        let png = Png::try_from(&PNG_FILE[..]).unwrap();
+        let report = Report::new(&png, &LsbOptions::default()).unwrap();
+
+        // One bit of each of the 4 samples of 50x50 pixels, after the lsb and envelope headers
+        assert_eq!(
+            report.rows[1].capacity.as_ref().unwrap(),
+            &((50 * 50 * 4 - lsb::HEADER_SIZE * 8) / 8 - envelope::HEADER_SIZE)
+        );
+        assert!(report.rows[2].capacity.is_err());
+        assert!(report.rows[4].capacity.is_ok());
+        assert!(report.to_string().starts_with("50x50 RGBA image"));
+    }
+
+    #[test]
+    fn test_exact_capacity() {
+        let png = Png::try_from(&PNG_FILE[..]).unwrap();
+        let options = LsbOptions::default();
+        let report = Report::new(&png, &options).unwrap();
+        let capacity = *report.rows[1].capacity.as_ref().unwrap();
+
+        let mut image = png.raw_image().unwrap();
+        let fits = Envelope::new(vec![b'a'; capacity]).as_bytes();
+        lsb::embed(&mut image, &fits, &options, None).unwrap();
+        assert_eq!(lsb::extract(&image, None).unwrap(), fits);
+
+        let too_long = Envelope::new(vec![b'a'; capacity + 1]).as_bytes();
+        assert!(lsb::embed(&mut image, &too_long, &options, None).is_err());
+    }
+}
@@ -1,7 +1,7 @@
 use std::fmt;
 use std::fmt::{Display, Formatter};
 
-use anyhow::{ensure, Context, Error, Result};
+use anyhow::{ensure, Error, Result};
 use crc::{Crc, CRC_32_ISO_HDLC};
 
 use crate::chunk_type::ChunkType;
@@ -27,9 +27,12 @@ impl Chunk {
         }
     }
 
+    #[cfg(test)]
     pub(crate) fn data_as_string(&self) -> Result<&str> {
         use std::str::from_utf8;
 
+        use anyhow::Context;
+
         from_utf8(&self.data).context("Chunk data cannot be converted to UTF-8")
     }
 
@@ -8,9 +8,32 @@ use anyhow::{ensure, Context, Error};
 pub struct ChunkType([u8; 4]);
 
 impl ChunkType {
+    pub const IHDR: ChunkType = ChunkType(*b"IHDR");
+    pub const IDAT: ChunkType = ChunkType(*b"IDAT");
+    pub const IEND: ChunkType = ChunkType(*b"IEND");
+
     pub fn bytes(&self) -> [u8; 4] {
         self.0
     }
+
+    /// Critical chunks are needed to display the image, ancillary ones are not
+    pub fn is_critical(&self) -> bool {
+        self.0[0] & 0x20 == 0
+    }
+
+    /// Public chunks are defined by the PNG specification or registered, private ones are not
+    pub fn is_public(&self) -> bool {
+        self.0[1] & 0x20 == 0
+    }
+
+    pub fn is_reserved_bit_valid(&self) -> bool {
+        self.0[2] & 0x20 == 0
+    }
+
+    /// Whether editors that do not know the chunk may copy it to a modified image
+    pub fn is_safe_to_copy(&self) -> bool {
+        self.0[3] & 0x20 != 0
+    }
 }
 
 impl PartialEq<Self> for ChunkType {
@@ -81,6 +104,30 @@ mod tests {
         assert_eq!(expected, actual);
     }
 
+    #[test]
+    pub fn test_chunk_type_is_critical() {
+        assert!(ChunkType::from_str("RuSt").unwrap().is_critical());
+        assert!(!ChunkType::from_str("ruSt").unwrap().is_critical());
+    }
+
+    #[test]
+    pub fn test_chunk_type_is_public() {
+        assert!(ChunkType::from_str("RUSt").unwrap().is_public());
+        assert!(!ChunkType::from_str("RuSt").unwrap().is_public());
+    }
+
+    #[test]
+    pub fn test_chunk_type_is_reserved_bit_valid() {
+        assert!(ChunkType::from_str("RuSt").unwrap().is_reserved_bit_valid());
+        assert!(!ChunkType::from_str("Rust").unwrap().is_reserved_bit_valid());
+    }
+
+    #[test]
+    pub fn test_chunk_type_is_safe_to_copy() {
+        assert!(ChunkType::from_str("RuSt").unwrap().is_safe_to_copy());
+        assert!(!ChunkType::from_str("RuST").unwrap().is_safe_to_copy());
+    }
+
     #[test]
     pub fn test_chunk_type_string() {
         let chunk = ChunkType::from_str("RuSt").unwrap();
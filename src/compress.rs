@@ -0,0 +1,140 @@
+use std::io::{Read, Write};
+
+use anyhow::{bail, ensure, Error, Result};
+use clap::ValueEnum;
+use flate2::read::DeflateDecoder;
+use flate2::write::DeflateEncoder;
+use flate2::Compression;
+
+/// Starts every compressed payload
+const MAGIC: [u8; 2] = [0xFF, b'Z'];
+
+/// Magic, algorithm and original size as u32
+pub const HEADER_SIZE: usize = MAGIC.len() + 1 + 4;
+
+/// Largest payload that is decompressed, against small payloads that expand enormously
+pub const MAX_SIZE: usize = 64 << 20;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum Algorithm {
+    Deflate,
+    Zstd,
+    Brotli,
+}
+
+impl Algorithm {
+    fn id(self) -> u8 {
+        match self {
+            Algorithm::Deflate => 1,
+            Algorithm::Zstd => 2,
+            Algorithm::Brotli => 3,
+        }
+    }
+
+    fn from_id(id: u8) -> Result<Algorithm, Error> {
+        Ok(match id {
+            1 => Algorithm::Deflate,
+            2 => Algorithm::Zstd,
+            3 => Algorithm::Brotli,
+            _ => bail!("Unknown compression algorithm {}", id),
+        })
+    }
+}
+
+/// Compresses the payload with the algorithm and its original size in front
+pub fn compress(payload: &[u8], algorithm: Algorithm) -> Result<Vec<u8>, Error> {
+    ensure!(
+        payload.len() <= MAX_SIZE,
+        "Payloads over {} bytes cannot be compressed",
+        MAX_SIZE
+    );
+
+    let mut compressed = MAGIC.to_vec();
+    compressed.push(algorithm.id());
+    compressed.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+
+    match algorithm {
+        Algorithm::Deflate => {
+            let mut encoder = DeflateEncoder::new(compressed, Compression::best());
+            encoder.write_all(payload)?;
+            Ok(encoder.finish()?)
+        }
+        Algorithm::Zstd => {
+            compressed.extend(zstd::encode_all(payload, 19)?);
+            Ok(compressed)
+        }
+        Algorithm::Brotli => {
+            {
+                let mut encoder = brotli::CompressorWriter::new(&mut compressed, 4096, 11, 22);
+                encoder.write_all(payload)?;
+            }
+            Ok(compressed)
+        }
+    }
+}
+
+fn is_compressed(data: &[u8]) -> bool {
+    data.starts_with(&MAGIC)
+}
+
+/// Decompresses a payload made by [`compress`], reading no more than its recorded size
+pub fn decompress(data: &[u8]) -> Result<Vec<u8>, Error> {
+    ensure!(
+        is_compressed(data) && data.len() >= HEADER_SIZE,
+        "Payload is not compressed"
+    );
+    let algorithm = Algorithm::from_id(data[2])?;
+    let size = u32::from_be_bytes(data[3..HEADER_SIZE].try_into()?) as usize;
+    ensure!(
+        size <= MAX_SIZE,
+        "Compressed payload claims {} bytes, more than {} are refused",
+        size,
+        MAX_SIZE
+    );
+
+    let data = &data[HEADER_SIZE..];
+    let decoder: Box<dyn Read> = match algorithm {
+        Algorithm::Deflate => Box::new(DeflateDecoder::new(data)),
+        Algorithm::Zstd => Box::new(zstd::Decoder::new(data)?),
+        Algorithm::Brotli => Box::new(brotli::Decompressor::new(data, 4096)),
+    };
+
+    // One byte more than allowed tells a payload that expands beyond its size
+    let mut payload = Vec::with_capacity(size);
+    decoder.take(size as u64 + 1).read_to_end(&mut payload)?;
+    ensure!(
+        payload.len() == size,
+        "Decompressed payload does not have its recorded size"
+    );
+
+    Ok(payload)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let message = "A message that repeats itself, repeats itself, repeats itself. ".repeat(20);
+        for algorithm in [Algorithm::Deflate, Algorithm::Zstd, Algorithm::Brotli] {
+            let compressed = compress(message.as_bytes(), algorithm).unwrap();
+            assert!(is_compressed(&compressed));
+            assert!(compressed.len() < message.len() / 4);
+            assert_eq!(decompress(&compressed).unwrap(), message.as_bytes());
+        }
+        assert!(!is_compressed(message.as_bytes()));
+    }
+
+    #[test]
+    fn test_size_cap() {
+        let mut compressed = compress(&[0; 10000], Algorithm::Zstd).unwrap();
+
+        // Claims fewer bytes than it expands to
+        compressed[3..HEADER_SIZE].copy_from_slice(&100u32.to_be_bytes());
+        assert!(decompress(&compressed).is_err());
+
+        compressed[3..HEADER_SIZE].copy_from_slice(&(MAX_SIZE as u32 + 1).to_be_bytes());
+        assert!(decompress(&compressed).is_err());
+    }
+}
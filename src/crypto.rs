@@ -0,0 +1,147 @@
+use anyhow::{anyhow, ensure, Error, Result};
+use argon2::{Algorithm, Argon2, Params, Version};
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use chacha20poly1305::{Key, XChaCha20Poly1305, XNonce};
+use rand::RngCore;
+
+const SALT_SIZE: usize = 16;
+const NONCE_SIZE: usize = 24;
+const TAG_SIZE: usize = 16;
+
+/// Size of the memory cost, iteration count and parallelism of Argon2id, each as u32
+const PARAMS_SIZE: usize = 12;
+
+/// Bytes the key derivation parameters, salt, nonce and authentication tag add to a message
+pub const OVERHEAD: usize = PARAMS_SIZE + SALT_SIZE + NONCE_SIZE + TAG_SIZE;
+
+/// Memory cost in KiB above which stored parameters are refused, so a crafted file cannot
+/// exhaust memory
+const MAX_MEMORY_COST: u32 = 1 << 20;
+
+/// Iteration count above which stored parameters are refused, so a crafted file cannot hang
+/// decoding
+const MAX_TIME_COST: u32 = 64;
+
+/// Degree of parallelism above which stored parameters are refused
+const MAX_PARALLELISM: u32 = 16;
+
+fn derive_key(password: &str, salt: &[u8], params: Params) -> Result<Key, Error> {
+    let mut key = Key::default();
+    Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+        .hash_password_into(password.as_bytes(), salt, &mut key)
+        .map_err(|error| anyhow!("Cannot derive key from password: {}", error))?;
+
+    Ok(key)
+}
+
+/// Encrypts the message with a key derived from the password with Argon2id
+///
+/// The parameters, salt and nonce are stored in front of the ciphertext and authenticated
+/// along with it.
+pub fn encrypt(message: &[u8], password: &str) -> Result<Vec<u8>, Error> {
+    let params = Params::default();
+    let mut header = [
+        params.m_cost().to_be_bytes(),
+        params.t_cost().to_be_bytes(),
+        params.p_cost().to_be_bytes(),
+    ]
+    .concat();
+    header.resize(PARAMS_SIZE + SALT_SIZE + NONCE_SIZE, 0);
+    rand::thread_rng().fill_bytes(&mut header[PARAMS_SIZE..]);
+
+    let (salt, nonce) = header[PARAMS_SIZE..].split_at(SALT_SIZE);
+    let ciphertext = XChaCha20Poly1305::new(&derive_key(password, salt, params)?)
+        .encrypt(
+            XNonce::from_slice(nonce),
+            Payload {
+                msg: message,
+                aad: &header,
+            },
+        )
+        .map_err(|_| anyhow!("Cannot encrypt message"))?;
+
+    Ok([header, ciphertext].concat())
+}
+
+/// Decrypts a message made by [`encrypt`], failing if the password is wrong or any byte changed
+pub fn decrypt(data: &[u8], password: &str) -> Result<Vec<u8>, Error> {
+    ensure!(data.len() >= OVERHEAD, "Data is too short to be encrypted");
+    let (header, ciphertext) = data.split_at(PARAMS_SIZE + SALT_SIZE + NONCE_SIZE);
+    let (params, rest) = header.split_at(PARAMS_SIZE);
+    let (salt, nonce) = rest.split_at(SALT_SIZE);
+
+    let cost = |i: usize| u32::from_be_bytes(params[i * 4..i * 4 + 4].try_into().unwrap());
+    ensure!(
+        cost(0) <= MAX_MEMORY_COST,
+        "Key derivation needs more memory than allowed"
+    );
+    ensure!(
+        cost(1) <= MAX_TIME_COST,
+        "Key derivation needs more iterations than allowed"
+    );
+    ensure!(
+        cost(2) <= MAX_PARALLELISM,
+        "Key derivation needs more parallelism than allowed"
+    );
+    let params = Params::new(cost(0), cost(1), cost(2), None)
+        .map_err(|error| anyhow!("Key derivation parameters are invalid: {}", error))?;
+
+    XChaCha20Poly1305::new(&derive_key(password, salt, params)?)
+        .decrypt(
+            XNonce::from_slice(nonce),
+            Payload {
+                msg: ciphertext,
+                aad: header,
+            },
+        )
+        .map_err(|_| anyhow!("Wrong password, or the message was tampered with"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encrypt_decrypt() {
+        let data = encrypt(b"Meet me at noon", "password").unwrap();
+        assert_eq!(data.len(), OVERHEAD + 15);
+        assert_eq!(&data[..4], &Params::DEFAULT_M_COST.to_be_bytes());
+        assert_eq!(decrypt(&data, "password").unwrap(), b"Meet me at noon");
+    }
+
+    #[test]
+    fn test_wrong_password() {
+        let data = encrypt(b"Meet me at noon", "password").unwrap();
+        let error = decrypt(&data, "Password").unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Wrong password, or the message was tampered with"
+        );
+    }
+
+    #[test]
+    fn test_tampered() {
+        let data = encrypt(b"Meet me at noon", "password").unwrap();
+        for i in [PARAMS_SIZE, PARAMS_SIZE + SALT_SIZE, data.len() - 1] {
+            let mut tampered = data.clone();
+            tampered[i] ^= 1;
+            assert!(decrypt(&tampered, "password").is_err());
+        }
+        assert!(decrypt(&data[..OVERHEAD - 1], "password").is_err());
+    }
+
+    #[test]
+    fn test_costly_params() {
+        let data = encrypt(b"Meet me at noon", "password").unwrap();
+        for (i, cost) in [
+            (0, MAX_MEMORY_COST + 1),
+            (1, u32::MAX),
+            (2, MAX_PARALLELISM + 1),
+        ] {
+            let mut crafted = data.clone();
+            crafted[i * 4..i * 4 + 4].copy_from_slice(&cost.to_be_bytes());
+            let error = decrypt(&crafted, "password").unwrap_err();
+            assert!(error.to_string().ends_with("than allowed"));
+        }
+    }
+}
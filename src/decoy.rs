@@ -0,0 +1,149 @@
+use anyhow::{anyhow, ensure, Error, Result};
+use argon2::Argon2;
+use chacha20poly1305::aead::{Aead, KeyInit};
+use chacha20poly1305::{Key, XChaCha20Poly1305, XNonce};
+use rand::seq::SliceRandom;
+use rand::RngCore;
+
+/// Number of slots in every container, whether they hold a payload or random bytes
+pub const SLOTS: usize = 4;
+
+const SALT_SIZE: usize = 16;
+const NONCE_SIZE: usize = 24;
+const TAG_SIZE: usize = 16;
+
+/// Bytes a slot adds to its payload: salt, nonce, payload length and authentication tag
+pub const SLOT_OVERHEAD: usize = SALT_SIZE + NONCE_SIZE + 4 + TAG_SIZE;
+
+/// Slot sizes are rounded up to a multiple of this, so they only hint at the longest payload
+const SLOT_ALIGN: usize = 64;
+
+fn slot_key(password: &str, salt: &[u8]) -> Result<Key, Error> {
+    let mut key = Key::default();
+    Argon2::default()
+        .hash_password_into(password.as_bytes(), salt, &mut key)
+        .map_err(|error| anyhow!("Cannot derive key from password: {}", error))?;
+
+    Ok(key)
+}
+
+/// Encrypts every payload with its own password into a random slot of a container
+///
+/// All slots have the same size and start out as random bytes, so without a password the
+/// container looks the same whether it holds one payload or [`SLOTS`] of them.
+pub fn seal(payloads: &[(&[u8], &str)]) -> Result<Vec<u8>, Error> {
+    ensure!(
+        (1..=SLOTS).contains(&payloads.len()),
+        "A container holds between 1 and {} payloads",
+        SLOTS
+    );
+    for (i, (_, password)) in payloads.iter().enumerate() {
+        ensure!(
+            payloads[..i].iter().all(|(_, other)| other != password),
+            "Every payload needs a different password"
+        );
+    }
+
+    let longest = payloads.iter().map(|(payload, _)| payload.len()).max();
+    let slot_size = (longest.unwrap_or(0) + SLOT_OVERHEAD).next_multiple_of(SLOT_ALIGN);
+    let mut rng = rand::thread_rng();
+    let mut container = vec![0; slot_size * SLOTS];
+    rng.fill_bytes(&mut container);
+
+    let mut slots: Vec<usize> = (0..SLOTS).collect();
+    slots.shuffle(&mut rng);
+
+    for ((payload, password), slot) in payloads.iter().zip(slots) {
+        let slot = &mut container[slot * slot_size..(slot + 1) * slot_size];
+        let (salt, rest) = slot.split_at_mut(SALT_SIZE);
+        let (nonce, body) = rest.split_at_mut(NONCE_SIZE);
+
+        // Random padding after the payload fills the slot
+        let mut plaintext = vec![0; body.len() - TAG_SIZE];
+        rng.fill_bytes(&mut plaintext);
+        plaintext[..4].copy_from_slice(&(payload.len() as u32).to_be_bytes());
+        plaintext[4..4 + payload.len()].copy_from_slice(payload);
+
+        let ciphertext = XChaCha20Poly1305::new(&slot_key(password, salt)?)
+            .encrypt(XNonce::from_slice(nonce), plaintext.as_slice())
+            .map_err(|_| anyhow!("Cannot encrypt payload"))?;
+        body.copy_from_slice(&ciphertext);
+    }
+
+    Ok(container)
+}
+
+/// Decrypts the payload of a container made by [`seal`] that the password belongs to
+///
+/// Every slot is tried, so the time it takes does not tell which one opened.
+pub fn open(container: &[u8], password: &str) -> Result<Vec<u8>, Error> {
+    let slot_size = container.len() / SLOTS;
+    ensure!(
+        container.len().is_multiple_of(SLOTS) && slot_size >= SLOT_OVERHEAD,
+        "Data is not a decoy container"
+    );
+
+    let mut opened = None;
+    for slot in container.chunks(slot_size) {
+        let (salt, rest) = slot.split_at(SALT_SIZE);
+        let (nonce, body) = rest.split_at(NONCE_SIZE);
+        let plaintext = XChaCha20Poly1305::new(&slot_key(password, salt)?)
+            .decrypt(XNonce::from_slice(nonce), body);
+        if let (Ok(plaintext), None) = (plaintext, &opened) {
+            opened = Some(plaintext);
+        }
+    }
+
+    let plaintext = opened.ok_or_else(|| anyhow!("No payload opens with this password"))?;
+    let len = u32::from_be_bytes(plaintext[..4].try_into()?) as usize;
+    ensure!(len <= plaintext.len() - 4, "Payload length is invalid");
+
+    Ok(plaintext[4..4 + len].to_vec())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_seal_open() {
+        let container = seal(&[
+            (b"Groceries: milk, eggs", "harmless"),
+            (b"The real secret is longer than the decoy", "real"),
+        ])
+        .unwrap();
+
+        assert_eq!(container.len() % (SLOTS * SLOT_ALIGN), 0);
+        assert_eq!(
+            open(&container, "harmless").unwrap(),
+            b"Groceries: milk, eggs"
+        );
+        assert_eq!(
+            open(&container, "real").unwrap(),
+            b"The real secret is longer than the decoy"
+        );
+        assert!(open(&container, "wrong").is_err());
+    }
+
+    #[test]
+    fn test_size_independent_of_count() {
+        let one = seal(&[(b"message", "a")]).unwrap();
+        let four = seal(&[(b"message", "a"), (b"b", "b"), (b"c", "c"), (b"d", "d")]).unwrap();
+        assert_eq!(one.len(), four.len());
+    }
+
+    #[test]
+    fn test_invalid_payloads() {
+        assert!(seal(&[]).is_err());
+        assert!(seal(&[(b"one", "same"), (b"two", "same")]).is_err());
+        assert!(seal(&[
+            (b"1", "a"),
+            (b"2", "b"),
+            (b"3", "c"),
+            (b"4", "d"),
+            (b"5", "e")
+        ])
+        .is_err());
+        assert!(open(&[0; 10], "password").is_err());
+    }
+}
@@ -0,0 +1,287 @@
+use anyhow::{bail, ensure, Error, Result};
+use crc::{Crc, CRC_32_ISO_HDLC};
+
+/// Number of copies of the parity size that start a frame, read back by majority vote
+pub const HEADER_SIZE: usize = 3;
+
+/// Size of the CRC-32 that follows the data inside the blocks, which catches miscorrections
+const CHECKSUM_SIZE: usize = 4;
+
+/// Bytes a frame adds besides the parity bytes
+pub const OVERHEAD: usize = HEADER_SIZE + CHECKSUM_SIZE;
+
+const CRC32: Crc<u32> = Crc::<u32>::new(&CRC_32_ISO_HDLC);
+
+/// Size of a full Reed-Solomon block, data and parity
+pub const BLOCK_SIZE: usize = 255;
+
+/// Exponent and logarithm tables of GF(256) with the polynomial x^8 + x^4 + x^3 + x^2 + 1
+///
+/// The exponent table is doubled so that products of two logarithms need no reduction.
+const fn tables() -> ([u8; 512], [u8; 256]) {
+    let mut exp = [0; 512];
+    let mut log = [0; 256];
+    let mut x: u16 = 1;
+    let mut i = 0;
+    while i < 255 {
+        exp[i] = x as u8;
+        log[x as usize] = i as u8;
+        x <<= 1;
+        if x & 0x100 != 0 {
+            x ^= 0x11D;
+        }
+        i += 1;
+    }
+    while i < 512 {
+        exp[i] = exp[i - 255];
+        i += 1;
+    }
+
+    (exp, log)
+}
+
+const EXP: [u8; 512] = tables().0;
+const LOG: [u8; 256] = tables().1;
+
+fn mul(a: u8, b: u8) -> u8 {
+    if a == 0 || b == 0 {
+        return 0;
+    }
+
+    EXP[LOG[a as usize] as usize + LOG[b as usize] as usize]
+}
+
+fn div(a: u8, b: u8) -> u8 {
+    if a == 0 {
+        return 0;
+    }
+
+    EXP[LOG[a as usize] as usize + 255 - LOG[b as usize] as usize]
+}
+
+/// Power of the generator element, alpha^e
+fn alpha(e: usize) -> u8 {
+    EXP[e % 255]
+}
+
+/// Evaluates a polynomial with its lowest coefficient first
+fn eval(poly: &[u8], x: u8) -> u8 {
+    poly.iter()
+        .rev()
+        .fold(0, |y, &coefficient| mul(y, x) ^ coefficient)
+}
+
+/// Generator polynomial (x - alpha^0)(x - alpha^1)...(x - alpha^(parity - 1)), highest first
+fn generator(parity: usize) -> Vec<u8> {
+    (0..parity).fold(vec![1], |poly, i| {
+        let mut product = poly.clone();
+        product.push(0);
+        for (j, &coefficient) in poly.iter().enumerate() {
+            product[j + 1] ^= mul(coefficient, alpha(i));
+        }
+        product
+    })
+}
+
+/// Appends the parity bytes to one block, the remainder of the division by the generator
+fn encode_block(data: &[u8], generator: &[u8]) -> Vec<u8> {
+    let mut remainder = vec![0; generator.len() - 1];
+    for &byte in data {
+        let factor = byte ^ remainder[0];
+        remainder.rotate_left(1);
+        *remainder.last_mut().unwrap() = 0;
+        for (r, &g) in remainder.iter_mut().zip(&generator[1..]) {
+            *r ^= mul(g, factor);
+        }
+    }
+
+    [data, &remainder[..]].concat()
+}
+
+/// Corrects one block in place and returns the number of corrected bytes
+///
+/// The byte at position p is the coefficient of x^(n - 1 - p). Berlekamp-Massey finds the
+/// error locator, a Chien search its roots and the Forney algorithm the error values.
+fn decode_block(block: &mut [u8], parity: usize) -> Result<usize, Error> {
+    let n = block.len();
+    let syndromes: Vec<u8> = (0..parity)
+        .map(|j| block.iter().fold(0, |y, &byte| mul(y, alpha(j)) ^ byte))
+        .collect();
+    if syndromes.iter().all(|&s| s == 0) {
+        return Ok(0);
+    }
+
+    // Berlekamp-Massey, polynomials with their lowest coefficient first
+    let mut locator = vec![1];
+    let mut previous = vec![1];
+    let mut errors = 0;
+    let mut shift = 1;
+    let mut previous_discrepancy = 1;
+    for i in 0..parity {
+        let discrepancy = (1..=errors.min(locator.len() - 1))
+            .fold(syndromes[i], |d, j| d ^ mul(locator[j], syndromes[i - j]));
+        if discrepancy == 0 {
+            shift += 1;
+            continue;
+        }
+
+        let scale = div(discrepancy, previous_discrepancy);
+        let mut next = locator.clone();
+        next.resize(next.len().max(previous.len() + shift), 0);
+        for (j, &coefficient) in previous.iter().enumerate() {
+            next[j + shift] ^= mul(scale, coefficient);
+        }
+
+        if 2 * errors <= i {
+            errors = i + 1 - errors;
+            previous = std::mem::replace(&mut locator, next);
+            previous_discrepancy = discrepancy;
+            shift = 1;
+        } else {
+            locator = next;
+            shift += 1;
+        }
+    }
+    while locator.last() == Some(&0) {
+        locator.pop();
+    }
+    if locator.len() - 1 != errors || 2 * errors > parity {
+        bail!("Too many errors to correct");
+    }
+
+    // Roots of the locator are the inverses of alpha^(n - 1 - p) for error positions p
+    let positions: Vec<usize> = (0..n)
+        .filter(|&p| eval(&locator, alpha(255 - (n - 1 - p) % 255)) == 0)
+        .collect();
+    if positions.len() != errors {
+        bail!("Too many errors to correct");
+    }
+
+    // Error evaluator S(x) * locator(x) mod x^parity and the formal derivative of the locator
+    let mut evaluator = vec![0; parity];
+    for (i, &s) in syndromes.iter().enumerate() {
+        for (j, &l) in locator.iter().enumerate().take(parity - i) {
+            evaluator[i + j] ^= mul(s, l);
+        }
+    }
+    let derivative: Vec<u8> = locator
+        .iter()
+        .enumerate()
+        .skip(1)
+        .map(|(i, &l)| if i % 2 == 1 { l } else { 0 })
+        .collect();
+
+    for &p in &positions {
+        let x = alpha(n - 1 - p);
+        let x_inv = div(1, x);
+        let denominator = eval(&derivative, x_inv);
+        ensure!(denominator != 0, "Too many errors to correct");
+        block[p] ^= mul(x, div(eval(&evaluator, x_inv), denominator));
+    }
+
+    Ok(errors)
+}
+
+/// Protects the data with `parity` Reed-Solomon bytes per block of up to 255 bytes
+///
+/// Every block corrects up to parity / 2 wrong bytes. The frame starts with the parity size
+/// repeated [`HEADER_SIZE`] times, and a CRC-32 of the data is protected along with it so that
+/// more errors than the blocks can correct are detected instead of miscorrected.
+pub fn encode(data: &[u8], parity: u8) -> Result<Vec<u8>, Error> {
+    let parity = parity as usize;
+    ensure!(
+        (2..=128).contains(&parity),
+        "Parity bytes per block must be between 2 and 128"
+    );
+
+    let generator = generator(parity);
+    let data = [data, &CRC32.checksum(data).to_be_bytes()].concat();
+
+    Ok(vec![parity as u8; HEADER_SIZE]
+        .into_iter()
+        .chain(
+            data.chunks(BLOCK_SIZE - parity)
+                .flat_map(|block| encode_block(block, &generator)),
+        )
+        .collect())
+}
+
+/// Reads a frame written by [`encode`] and returns the data with the number of corrected bytes
+pub fn decode(frame: &[u8]) -> Result<(Vec<u8>, usize), Error> {
+    ensure!(
+        frame.len() >= HEADER_SIZE,
+        "Error correction frame is too short"
+    );
+    let parity = match frame[..HEADER_SIZE] {
+        [a, b, _] | [a, _, b] | [_, a, b] if a == b => a as usize,
+        _ => bail!("Error correction header is corrupted"),
+    };
+    ensure!(
+        (2..=128).contains(&parity),
+        "Error correction header is corrupted"
+    );
+
+    let mut data = Vec::new();
+    let mut corrected = 0;
+    for block in frame[HEADER_SIZE..].chunks(BLOCK_SIZE) {
+        ensure!(block.len() > parity, "Error correction frame is truncated");
+        let mut block = block.to_vec();
+        corrected += decode_block(&mut block, parity)?;
+        data.extend_from_slice(&block[..block.len() - parity]);
+    }
+
+    ensure!(
+        data.len() >= CHECKSUM_SIZE,
+        "Error correction frame is truncated"
+    );
+    let checksum = data.split_off(data.len() - CHECKSUM_SIZE);
+    ensure!(
+        CRC32.checksum(&data).to_be_bytes() == checksum.as_slice(),
+        "Too many errors to correct"
+    );
+
+    Ok((data, corrected))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn corrupt(frame: &mut [u8], positions: &[usize]) {
+        positions
+            .iter()
+            .for_each(|&p| frame[p] = frame[p].wrapping_add(0x5A) ^ 0x0F);
+    }
+
+    #[test]
+    fn test_no_errors() {
+        let data = b"Reed-Solomon protects this message";
+        let frame = encode(data, 8).unwrap();
+        assert_eq!(frame.len(), OVERHEAD + data.len() + 8);
+        assert_eq!(decode(&frame).unwrap(), (data.to_vec(), 0));
+    }
+
+    #[test]
+    fn test_correct_errors() {
+        let data: Vec<u8> = (0..600).map(|i| (i * 7 % 256) as u8).collect();
+        let mut frame = encode(&data, 16).unwrap();
+
+        // Seven errors in the first block, one in the last and a damaged header byte
+        corrupt(&mut frame, &[0, 3, 10, 50, 100, 150, 200, 257, 600]);
+        assert_eq!(decode(&frame).unwrap(), (data, 9 - 1));
+    }
+
+    #[test]
+    fn test_too_many_errors() {
+        let data = b"Reed-Solomon protects this message";
+        let mut frame = encode(data, 4).unwrap();
+        corrupt(&mut frame, &[5, 6, 7]);
+        assert!(decode(&frame).is_err());
+    }
+
+    #[test]
+    fn test_invalid_parity() {
+        assert!(encode(b"data", 1).is_err());
+        assert!(encode(b"data", 129).is_err());
+    }
+}
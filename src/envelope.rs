@@ -0,0 +1,304 @@
+use anyhow::{bail, ensure, Error, Result};
+use crc::{Crc, CRC_32_ISO_HDLC};
+
+use crate::ecc;
+
+/// Starts every envelope, 0x89 never starts UTF-8 so legacy text messages cannot match
+const MAGIC: [u8; 4] = [0x89, b'P', b'M', b'E'];
+
+pub const VERSION: u8 = 1;
+
+/// Magic, version, flags, encryption, content type, body checksum and length
+pub const HEADER_SIZE: usize = MAGIC.len() + 1 + 1 + 1 + 1 + 4 + 4;
+
+/// Filename and MIME type lengths in front of a file or directory
+pub const METADATA_SIZE: usize = 1 + 1;
+
+const COMPRESSED: u8 = 1;
+const ECC: u8 = 1 << 1;
+const SIGNED: u8 = 1 << 2;
+
+const CRC32: Crc<u32> = Crc::<u32>::new(&CRC_32_ISO_HDLC);
+
+/// How the body of an envelope is encrypted
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Encryption {
+    None,
+    /// With a password, by --encrypt
+    Password,
+    /// To public keys, by --recipient or --to
+    Recipients,
+    /// Into slots with one password each, by --decoy
+    Decoy,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ContentType {
+    /// UTF-8 message
+    Text,
+    Binary,
+    /// Tar stream of a directory
+    Archive,
+}
+
+/// Versioned header that tells decode how the message was hidden, followed by the body
+///
+/// Every method hides the same envelope. When error correction is used, the whole envelope
+/// is wrapped in an error correction frame, which [`Envelope::find`] recognises.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Envelope {
+    pub compressed: bool,
+    pub ecc: bool,
+    /// The message chunk has a signature chunk next to it
+    pub signed: bool,
+    pub encryption: Encryption,
+    pub content_type: ContentType,
+    /// Message after compression and encryption
+    pub body: Vec<u8>,
+}
+
+impl Envelope {
+    /// Envelope of a plain text body
+    pub fn new(body: Vec<u8>) -> Envelope {
+        Envelope {
+            compressed: false,
+            ecc: false,
+            signed: false,
+            encryption: Encryption::None,
+            content_type: ContentType::Text,
+            body,
+        }
+    }
+
+    pub fn as_bytes(&self) -> Vec<u8> {
+        let flags = [
+            (self.compressed, COMPRESSED),
+            (self.ecc, ECC),
+            (self.signed, SIGNED),
+        ]
+        .iter()
+        .filter(|(set, _)| *set)
+        .fold(0, |flags, (_, flag)| flags | flag);
+        let encryption = match self.encryption {
+            Encryption::None => 0,
+            Encryption::Password => 1,
+            Encryption::Recipients => 2,
+            Encryption::Decoy => 3,
+        };
+        let content_type = match self.content_type {
+            ContentType::Text => 0,
+            ContentType::Binary => 1,
+            ContentType::Archive => 2,
+        };
+
+        MAGIC
+            .iter()
+            .copied()
+            .chain([VERSION, flags, encryption, content_type])
+            .chain(CRC32.checksum(&self.body).to_be_bytes())
+            .chain((self.body.len() as u32).to_be_bytes())
+            .chain(self.body.iter().copied())
+            .collect()
+    }
+
+    /// Finds an envelope in extracted data, inside an error correction frame or not
+    ///
+    /// Returns the envelope with the number of corrected bytes, or nothing for legacy data
+    /// that holds the raw message.
+    pub fn find(data: &[u8]) -> Option<Result<(Envelope, usize), Error>> {
+        if data.starts_with(&MAGIC) {
+            return Some(Envelope::try_from(data).map(|envelope| (envelope, 0)));
+        }
+
+        match ecc::decode(data) {
+            Ok((data, corrected)) if data.starts_with(&MAGIC) => {
+                Some(Envelope::try_from(data.as_slice()).map(|envelope| (envelope, corrected)))
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Splits off the next `len` bytes of the envelope
+fn take<'a>(data: &mut &'a [u8], len: usize) -> Result<&'a [u8], Error> {
+    ensure!(data.len() >= len, "Envelope is truncated");
+    let (taken, rest) = data.split_at(len);
+    *data = rest;
+
+    Ok(taken)
+}
+
+/// Reads a string of up to 255 bytes after its length, empty meaning none
+fn take_string(data: &mut &[u8]) -> Result<Option<String>, Error> {
+    let len = take(data, 1)?[0] as usize;
+    let string = take(data, len)?;
+
+    Ok((!string.is_empty())
+        .then(|| String::from_utf8(string.to_vec()))
+        .transpose()?)
+}
+
+impl TryFrom<&[u8]> for Envelope {
+    type Error = Error;
+
+    fn try_from(data: &[u8]) -> Result<Self, Error> {
+        ensure!(data.starts_with(&MAGIC), "Data is not an envelope");
+        let mut data = &data[MAGIC.len()..];
+        let [version, flags, encryption, content_type] = take(&mut data, 4)?.try_into()?;
+        ensure!(
+            version == VERSION,
+            "Envelope version {} is not supported, upgrade pngme",
+            version
+        );
+
+        let encryption = match encryption {
+            0 => Encryption::None,
+            1 => Encryption::Password,
+            2 => Encryption::Recipients,
+            3 => Encryption::Decoy,
+            _ => bail!("Unknown encryption {}", encryption),
+        };
+        let content_type = match content_type {
+            0 => ContentType::Text,
+            1 => ContentType::Binary,
+            2 => ContentType::Archive,
+            _ => bail!("Unknown content type {}", content_type),
+        };
+
+        let checksum = u32::from_be_bytes(take(&mut data, 4)?.try_into()?);
+        let len = u32::from_be_bytes(take(&mut data, 4)?.try_into()?) as usize;
+        let body = take(&mut data, len)?.to_vec();
+        ensure!(
+            CRC32.checksum(&body) == checksum,
+            "Envelope checksum does not match, the message is damaged"
+        );
+
+        Ok(Envelope {
+            compressed: flags & COMPRESSED != 0,
+            ecc: flags & ECC != 0,
+            signed: flags & SIGNED != 0,
+            encryption,
+            content_type,
+            body,
+        })
+    }
+}
+
+/// Name and MIME type of a hidden file or directory
+///
+/// They are stored in front of the content, so they are compressed and encrypted with it.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Metadata {
+    /// Name of the hidden file, without its directory
+    pub filename: Option<String>,
+    pub mime_type: Option<String>,
+}
+
+impl Metadata {
+    /// Puts the metadata in front of the content, fails on strings longer than 255 bytes
+    pub fn prepend(&self, content: &[u8]) -> Result<Vec<u8>, Error> {
+        let mut data = Vec::with_capacity(METADATA_SIZE + content.len());
+        for string in [&self.filename, &self.mime_type] {
+            let string = string.as_deref().unwrap_or_default().as_bytes();
+            ensure!(
+                string.len() <= u8::MAX as usize,
+                "{} is too long to store",
+                String::from_utf8_lossy(string)
+            );
+            data.push(string.len() as u8);
+            data.extend_from_slice(string);
+        }
+        data.extend_from_slice(content);
+
+        Ok(data)
+    }
+
+    /// Splits the metadata written by [`Metadata::prepend`] off the content
+    pub fn split(mut data: &[u8]) -> Result<(Metadata, &[u8]), Error> {
+        let metadata = Metadata {
+            filename: take_string(&mut data)?,
+            mime_type: take_string(&mut data)?,
+        };
+
+        Ok((metadata, data))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn envelope() -> Envelope {
+        Envelope {
+            compressed: true,
+            signed: true,
+            encryption: Encryption::Recipients,
+            content_type: ContentType::Binary,
+            ..Envelope::new(b"body".to_vec())
+        }
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let envelope = envelope();
+        let bytes = envelope.as_bytes();
+        assert_eq!(bytes.len(), HEADER_SIZE + 4);
+        assert_eq!(Envelope::try_from(bytes.as_slice()).unwrap(), envelope);
+
+        // Trailing bytes after the body are ignored
+        let padded = [bytes.as_slice(), &[0; 10]].concat();
+        assert_eq!(Envelope::try_from(padded.as_slice()).unwrap(), envelope);
+
+        let mut damaged = bytes.clone();
+        *damaged.last_mut().unwrap() ^= 1;
+        assert!(Envelope::try_from(damaged.as_slice()).is_err());
+    }
+
+    #[test]
+    fn test_metadata() {
+        let metadata = Metadata {
+            filename: Some(String::from("notes.txt")),
+            mime_type: Some(String::from("text/plain")),
+        };
+        let data = metadata.prepend(b"content").unwrap();
+        assert_eq!(
+            data.len(),
+            METADATA_SIZE + "notes.txt".len() + "text/plain".len() + "content".len()
+        );
+        assert_eq!(Metadata::split(&data).unwrap(), (metadata, &b"content"[..]));
+
+        let data = Metadata::default().prepend(b"content").unwrap();
+        assert_eq!(
+            Metadata::split(&data).unwrap(),
+            (Metadata::default(), &b"content"[..])
+        );
+        assert!(Metadata::split(&[9, b'a']).is_err());
+
+        let long = Metadata {
+            filename: Some("a".repeat(256)),
+            mime_type: None,
+        };
+        assert!(long.prepend(b"content").is_err());
+    }
+
+    #[test]
+    fn test_find() {
+        let envelope = Envelope {
+            ecc: true,
+            ..envelope()
+        };
+        let mut frame = ecc::encode(&envelope.as_bytes(), 8).unwrap();
+        frame[5] ^= 0xFF;
+
+        assert_eq!(Envelope::find(&frame).unwrap().unwrap(), (envelope, 1));
+        assert!(Envelope::find(b"Legacy raw message").is_none());
+    }
+
+    #[test]
+    fn test_unsupported_version() {
+        let mut bytes = envelope().as_bytes();
+        bytes[MAGIC.len()] = VERSION + 1;
+        assert!(Envelope::try_from(bytes.as_slice()).is_err());
+        assert!(Envelope::try_from(&bytes[..HEADER_SIZE]).is_err());
+    }
+}
@@ -0,0 +1,196 @@
+use anyhow::{bail, ensure, Context, Error, Result};
+
+use crate::chunk::Chunk;
+use crate::chunk_type::ChunkType;
+use crate::png::Png;
+
+/// Size of the sequence number and total count that start every fragment
+pub const HEADER_SIZE: usize = 4;
+
+/// Splits the payload into `count` chunks of about the same size, rotating through the types
+///
+/// Every fragment starts with its sequence number and the total count, both as u16.
+pub fn split(payload: &[u8], chunk_types: &[ChunkType], count: usize) -> Result<Vec<Chunk>, Error> {
+    ensure!(!chunk_types.is_empty(), "Chunk type is missing");
+    ensure!(
+        (1..=u16::MAX as usize).contains(&count),
+        "Number of fragments must be between 1 and {}",
+        u16::MAX
+    );
+    ensure!(
+        count <= payload.len().max(1),
+        "Cannot split {} bytes into {} fragments",
+        payload.len(),
+        count
+    );
+
+    let size = payload.len().div_ceil(count).max(1);
+    ensure!(
+        size + HEADER_SIZE <= Png::MAX_CHUNK_LENGTH,
+        "Fragments are larger than a chunk can be"
+    );
+
+    Ok((0..count)
+        .map(|seq| {
+            let data = payload.get(seq * size..((seq + 1) * size).min(payload.len()));
+            let header = [(seq as u16).to_be_bytes(), (count as u16).to_be_bytes()].concat();
+
+            Chunk::new(
+                chunk_types[seq % chunk_types.len()].clone(),
+                [header.as_slice(), data.unwrap_or_default()].concat(),
+            )
+        })
+        .collect())
+}
+
+/// Whether the data starts like a fragment, with a sequence number below the total count
+pub fn has_header(data: &[u8]) -> bool {
+    data.len() >= HEADER_SIZE
+        && u16::from_be_bytes([data[0], data[1]]) < u16::from_be_bytes([data[2], data[3]])
+}
+
+/// Collects the fragments in chunks of the given types and joins them in sequence order
+pub fn reassemble(png: &Png, chunk_types: &[ChunkType]) -> Result<Vec<u8>, Error> {
+    let mut fragments: Vec<(usize, usize, &[u8])> = png
+        .chunks
+        .iter()
+        .filter(|chunk| chunk_types.contains(&chunk.r#type))
+        .map(|chunk| {
+            ensure!(
+                chunk.data.len() >= HEADER_SIZE,
+                "{} chunk is too short to be a fragment",
+                chunk.r#type
+            );
+            let seq = u16::from_be_bytes([chunk.data[0], chunk.data[1]]) as usize;
+            let total = u16::from_be_bytes([chunk.data[2], chunk.data[3]]) as usize;
+
+            Ok((seq, total, &chunk.data[HEADER_SIZE..]))
+        })
+        .collect::<Result<_, Error>>()?;
+
+    let total = fragments.first().context("No fragments found")?.1;
+    ensure!(
+        fragments.iter().all(|fragment| fragment.1 == total),
+        "Fragments disagree on the total count"
+    );
+
+    fragments.sort_by_key(|fragment| fragment.0);
+    // Copies of a fragment are harmless, but two different fragments with one number are not
+    if let Some(pair) = fragments
+        .windows(2)
+        .find(|pair| pair[0].0 == pair[1].0 && pair[0].2 != pair[1].2)
+    {
+        bail!(
+            "Fragment {} of {} appears twice with different data",
+            pair[0].0 + 1,
+            total
+        );
+    }
+    fragments.dedup_by_key(|fragment| fragment.0);
+
+    let missing: Vec<String> = (0..total)
+        .filter(|seq| {
+            fragments
+                .binary_search_by_key(seq, |fragment| fragment.0)
+                .is_err()
+        })
+        .map(|seq| (seq + 1).to_string())
+        .collect();
+    ensure!(
+        missing.is_empty(),
+        "Missing fragments {} of {}",
+        missing.join(", "),
+        total
+    );
+    ensure!(
+        fragments.len() == total,
+        "Fragment numbers exceed the total count"
+    );
+
+    Ok(fragments
+        .iter()
+        .flat_map(|fragment| fragment.2.iter().copied())
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use crate::png::tests::PNG_FILE;
+
+    use super::*;
+
+    fn types(names: &[&str]) -> Vec<ChunkType> {
+        names
+            .iter()
+            .map(|name| ChunkType::from_str(name).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_split_reassemble() {
+        let chunk_types = types(&["ruSt", "teSt"]);
+        let payload = b"A payload that is spread over many small chunks";
+        let mut png = Png::try_from(&PNG_FILE[..]).unwrap();
+
+        let chunks = split(payload, &chunk_types, 5).unwrap();
+        assert_eq!(chunks.len(), 5);
+        assert_eq!(chunks[1].r#type.to_string(), "teSt");
+        assert!(chunks.iter().all(|chunk| has_header(&chunk.data)));
+        assert!(!has_header(&[0, 2, 0, 2]));
+
+        // Order in the file does not matter
+        chunks
+            .into_iter()
+            .rev()
+            .for_each(|chunk| png.append_chunk(chunk));
+        assert_eq!(reassemble(&png, &chunk_types).unwrap(), payload);
+    }
+
+    #[test]
+    fn test_missing_fragment() {
+        let chunk_types = types(&["ruSt"]);
+        let mut png = Png::try_from(&PNG_FILE[..]).unwrap();
+        split(b"0123456789", &chunk_types, 4)
+            .unwrap()
+            .into_iter()
+            .enumerate()
+            .filter(|(seq, _)| *seq != 2)
+            .for_each(|(_, chunk)| png.append_chunk(chunk));
+
+        let error = reassemble(&png, &chunk_types).unwrap_err();
+        assert_eq!(error.to_string(), "Missing fragments 3 of 4");
+    }
+
+    #[test]
+    fn test_duplicate_fragment() {
+        let chunk_types = types(&["ruSt"]);
+        let mut png = Png::try_from(&PNG_FILE[..]).unwrap();
+        let chunks = split(b"0123456789", &chunk_types, 2).unwrap();
+        chunks
+            .iter()
+            .for_each(|chunk| png.append_chunk(chunk.clone()));
+
+        // An identical copy is ignored
+        png.append_chunk(chunks[1].clone());
+        assert_eq!(reassemble(&png, &chunk_types).unwrap(), b"0123456789");
+
+        let mut forged = chunks[1].data.clone();
+        forged[HEADER_SIZE] ^= 1;
+        png.append_chunk(Chunk::new(chunk_types[0].clone(), forged));
+        let error = reassemble(&png, &chunk_types).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Fragment 2 of 2 appears twice with different data"
+        );
+    }
+
+    #[test]
+    fn test_split_invalid_count() {
+        let chunk_types = types(&["ruSt"]);
+        assert!(split(b"abc", &chunk_types, 0).is_err());
+        assert!(split(b"abc", &chunk_types, 4).is_err());
+        assert_eq!(split(b"", &chunk_types, 1).unwrap().len(), 1);
+    }
+}
@@ -0,0 +1,730 @@
+use std::fmt;
+use std::fmt::{Display, Formatter};
+use std::io::{Read, Write};
+use std::str::FromStr;
+
+use anyhow::{anyhow, bail, ensure, Context, Error, Result};
+use flate2::read::ZlibDecoder;
+use flate2::write::ZlibEncoder;
+use flate2::Compression;
+
+use crate::chunk::Chunk;
+use crate::chunk_type::ChunkType;
+use crate::util::slice_4_bytes;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ColorType {
+    Grayscale,
+    Rgb,
+    Indexed,
+    GrayscaleAlpha,
+    Rgba,
+}
+
+impl ColorType {
+    pub fn channels(&self) -> usize {
+        match self {
+            ColorType::Grayscale | ColorType::Indexed => 1,
+            ColorType::GrayscaleAlpha => 2,
+            ColorType::Rgb => 3,
+            ColorType::Rgba => 4,
+        }
+    }
+
+    pub fn byte(&self) -> u8 {
+        match self {
+            ColorType::Grayscale => 0,
+            ColorType::Rgb => 2,
+            ColorType::Indexed => 3,
+            ColorType::GrayscaleAlpha => 4,
+            ColorType::Rgba => 6,
+        }
+    }
+
+    fn allowed_bit_depths(&self) -> &'static [u8] {
+        match self {
+            ColorType::Grayscale => &[1, 2, 4, 8, 16],
+            ColorType::Indexed => &[1, 2, 4, 8],
+            ColorType::Rgb | ColorType::GrayscaleAlpha | ColorType::Rgba => &[8, 16],
+        }
+    }
+}
+
+impl TryFrom<u8> for ColorType {
+    type Error = Error;
+
+    fn try_from(value: u8) -> Result<Self, Error> {
+        Ok(match value {
+            0 => ColorType::Grayscale,
+            2 => ColorType::Rgb,
+            3 => ColorType::Indexed,
+            4 => ColorType::GrayscaleAlpha,
+            6 => ColorType::Rgba,
+            _ => bail!(
+                "Invalid color type, {} is not one of 0, 2, 3, 4 or 6",
+                value
+            ),
+        })
+    }
+}
+
+impl Display for ColorType {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            ColorType::Grayscale => "grayscale",
+            ColorType::Rgb => "RGB",
+            ColorType::Indexed => "indexed",
+            ColorType::GrayscaleAlpha => "grayscale with alpha",
+            ColorType::Rgba => "RGBA",
+        };
+
+        write!(f, "{}", name)
+    }
+}
+
+/// Image header, the contents of the IHDR chunk
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Ihdr {
+    pub width: u32,
+    pub height: u32,
+    pub bit_depth: u8,
+    pub color_type: ColorType,
+    pub interlaced: bool,
+}
+
+impl Ihdr {
+    pub fn bits_per_pixel(&self) -> usize {
+        self.color_type.channels() * self.bit_depth as usize
+    }
+
+    /// Number of bytes in a scanline of the given width, without the filter type byte
+    pub fn stride(&self, width: usize) -> usize {
+        (width * self.bits_per_pixel()).div_ceil(8)
+    }
+
+    pub fn as_chunk(&self) -> Chunk {
+        let data = self
+            .width
+            .to_be_bytes()
+            .iter()
+            .chain(self.height.to_be_bytes().iter())
+            .copied()
+            .chain([
+                self.bit_depth,
+                self.color_type.byte(),
+                0,
+                0,
+                self.interlaced as u8,
+            ])
+            .collect();
+
+        Chunk::new(ChunkType::IHDR, data)
+    }
+}
+
+impl TryFrom<&Chunk> for Ihdr {
+    type Error = Error;
+
+    fn try_from(chunk: &Chunk) -> Result<Self, Error> {
+        ensure!(
+            chunk.r#type == ChunkType::IHDR,
+            "Invalid IHDR, chunk type is {}",
+            chunk.r#type
+        );
+        ensure!(
+            chunk.data.len() == 13,
+            "Invalid IHDR, chunk data must be 13 bytes long"
+        );
+
+        let width = u32::from_be_bytes(slice_4_bytes(&chunk.data, 0)?);
+        let height = u32::from_be_bytes(slice_4_bytes(&chunk.data, 4)?);
+        let bit_depth = chunk.data[8];
+        let color_type = ColorType::try_from(chunk.data[9])?;
+
+        ensure!(
+            width > 0 && height > 0,
+            "Invalid IHDR, width and height must be greater than zero"
+        );
+        ensure!(
+            color_type.allowed_bit_depths().contains(&bit_depth),
+            "Invalid IHDR, bit depth {} is not allowed for {} images",
+            bit_depth,
+            color_type
+        );
+        ensure!(
+            chunk.data[10] == 0 && chunk.data[11] == 0,
+            "Invalid IHDR, unknown compression or filter method"
+        );
+
+        let interlaced = match chunk.data[12] {
+            0 => false,
+            1 => true,
+            method => bail!("Invalid IHDR, unknown interlace method {}", method),
+        };
+
+        Ok(Ihdr {
+            width,
+            height,
+            bit_depth,
+            color_type,
+            interlaced,
+        })
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FilterType {
+    None,
+    Sub,
+    Up,
+    Average,
+    Paeth,
+}
+
+impl FilterType {
+    const ALL: [FilterType; 5] = [
+        FilterType::None,
+        FilterType::Sub,
+        FilterType::Up,
+        FilterType::Average,
+        FilterType::Paeth,
+    ];
+}
+
+impl TryFrom<u8> for FilterType {
+    type Error = Error;
+
+    fn try_from(value: u8) -> Result<Self, Error> {
+        FilterType::ALL
+            .get(value as usize)
+            .copied()
+            .with_context(|| format!("Invalid filter type {}", value))
+    }
+}
+
+/// How a filter type is chosen for every scanline
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FilterStrategy {
+    Fixed(FilterType),
+    /// Picks the filter with the minimum sum of absolute differences per scanline
+    Adaptive,
+}
+
+impl FromStr for FilterStrategy {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Error> {
+        Ok(match s.to_ascii_lowercase().as_str() {
+            "none" => FilterStrategy::Fixed(FilterType::None),
+            "sub" => FilterStrategy::Fixed(FilterType::Sub),
+            "up" => FilterStrategy::Fixed(FilterType::Up),
+            "average" => FilterStrategy::Fixed(FilterType::Average),
+            "paeth" => FilterStrategy::Fixed(FilterType::Paeth),
+            "adaptive" => FilterStrategy::Adaptive,
+            _ => bail!("Invalid filter, use one of none, sub, up, average, paeth or adaptive"),
+        })
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct EncodeOptions {
+    pub filter: FilterStrategy,
+    pub interlaced: bool,
+    /// Deflate compression level between 0 and 9
+    pub level: u32,
+    /// Maximum size of the data of a single IDAT chunk
+    pub idat_size: usize,
+}
+
+impl Default for EncodeOptions {
+    fn default() -> Self {
+        EncodeOptions {
+            filter: FilterStrategy::Adaptive,
+            interlaced: false,
+            level: 6,
+            idat_size: 8192,
+        }
+    }
+}
+
+/// Decoded image data: unfiltered, non-interlaced scanlines without filter type bytes
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RawImage {
+    pub width: u32,
+    pub height: u32,
+    pub bit_depth: u8,
+    pub color_type: ColorType,
+    pub data: Vec<u8>,
+}
+
+/// Largest decoded image that is accepted, against headers that claim enormous sizes
+pub const MAX_IMAGE_SIZE: usize = 1 << 30;
+
+/// Starting column, starting row, column step and row step of the seven Adam7 passes
+const ADAM7: [(usize, usize, usize, usize); 7] = [
+    (0, 0, 8, 8),
+    (4, 0, 8, 8),
+    (0, 4, 4, 8),
+    (2, 0, 4, 4),
+    (0, 2, 2, 4),
+    (1, 0, 2, 2),
+    (0, 1, 1, 2),
+];
+
+impl RawImage {
+    /// Inflates, unfilters and de-interlaces the concatenated data of the IDAT chunks
+    pub fn decode(ihdr: &Ihdr, compressed: &[u8]) -> Result<RawImage, Error> {
+        let width = ihdr.width as usize;
+        let height = ihdr.height as usize;
+        let bits = ihdr.bits_per_pixel();
+        let expected = filtered_len(ihdr)?;
+
+        // One byte more than expected tells a stream that inflates beyond the image
+        let mut filtered = Vec::new();
+        ZlibDecoder::new(compressed)
+            .take(expected as u64 + 1)
+            .read_to_end(&mut filtered)
+            .context("Cannot inflate image data")?;
+
+        let stride = ihdr.stride(width);
+        let mut data = vec![0; stride * height];
+        let mut cur = 0;
+
+        for (x0, y0, dx, dy, pass_width, pass_height) in passes(ihdr.interlaced, width, height) {
+            let pass_stride = ihdr.stride(pass_width);
+            let mut prev = vec![0; pass_stride];
+
+            for row in 0..pass_height {
+                ensure!(
+                    cur + pass_stride < filtered.len(),
+                    "Invalid image data, inflated data is shorter than the image"
+                );
+
+                let filter = FilterType::try_from(filtered[cur])?;
+                let mut line = filtered[cur + 1..cur + 1 + pass_stride].to_vec();
+                unfilter(filter, &mut line, &prev, filter_bpp(bits));
+                cur += pass_stride + 1;
+
+                let y = y0 + row * dy;
+                let target = &mut data[y * stride..(y + 1) * stride];
+                if dx == 1 {
+                    target.copy_from_slice(&line);
+                } else {
+                    for col in 0..pass_width {
+                        copy_pixel(&line, col, target, x0 + col * dx, bits);
+                    }
+                }
+
+                prev = line;
+            }
+        }
+
+        ensure!(
+            cur == filtered.len(),
+            "Invalid image data, inflated data is longer than the image"
+        );
+
+        Ok(RawImage {
+            width: ihdr.width,
+            height: ihdr.height,
+            bit_depth: ihdr.bit_depth,
+            color_type: ihdr.color_type,
+            data,
+        })
+    }
+
+    /// Filters, optionally interlaces and deflates the image into a zlib stream for IDAT chunks
+    pub fn encode(&self, options: &EncodeOptions) -> Result<Vec<u8>, Error> {
+        ensure!(
+            options.level <= 9,
+            "Invalid compression level, it must be between 0 and 9"
+        );
+
+        let ihdr = self.ihdr(options.interlaced);
+        let width = self.width as usize;
+        let height = self.height as usize;
+        let bits = ihdr.bits_per_pixel();
+        let stride = ihdr.stride(width);
+        ensure!(
+            self.data.len() == stride * height,
+            "Invalid image data, expected {} bytes but found {}",
+            stride * height,
+            self.data.len()
+        );
+
+        let mut filtered = Vec::with_capacity((stride + 1) * height);
+        for (x0, y0, dx, dy, pass_width, pass_height) in passes(options.interlaced, width, height) {
+            let pass_stride = ihdr.stride(pass_width);
+            let mut prev = vec![0; pass_stride];
+
+            for row in 0..pass_height {
+                let y = y0 + row * dy;
+                let source = &self.data[y * stride..(y + 1) * stride];
+                let line = if dx == 1 {
+                    source.to_vec()
+                } else {
+                    let mut line = vec![0; pass_stride];
+                    for col in 0..pass_width {
+                        copy_pixel(source, x0 + col * dx, &mut line, col, bits);
+                    }
+                    line
+                };
+
+                filter_line(
+                    options.filter,
+                    &line,
+                    &prev,
+                    filter_bpp(bits),
+                    &mut filtered,
+                );
+                prev = line;
+            }
+        }
+
+        let mut encoder = ZlibEncoder::new(Vec::new(), Compression::new(options.level));
+        encoder.write_all(&filtered)?;
+
+        Ok(encoder.finish()?)
+    }
+
+    pub fn ihdr(&self, interlaced: bool) -> Ihdr {
+        Ihdr {
+            width: self.width,
+            height: self.height,
+            bit_depth: self.bit_depth,
+            color_type: self.color_type,
+            interlaced,
+        }
+    }
+}
+
+/// Lists (x0, y0, dx, dy, width, height) of the non-empty passes of an image
+fn passes(
+    interlaced: bool,
+    width: usize,
+    height: usize,
+) -> Vec<(usize, usize, usize, usize, usize, usize)> {
+    if !interlaced {
+        return vec![(0, 0, 1, 1, width, height)];
+    }
+
+    ADAM7
+        .iter()
+        .filter(|&&(x0, y0, _, _)| x0 < width && y0 < height)
+        .map(|&(x0, y0, dx, dy)| {
+            (
+                x0,
+                y0,
+                dx,
+                dy,
+                (width - x0).div_ceil(dx),
+                (height - y0).div_ceil(dy),
+            )
+        })
+        .collect()
+}
+
+/// Length of the inflated image data with filter type bytes, refusing implausible sizes
+fn filtered_len(ihdr: &Ihdr) -> Result<usize, Error> {
+    let too_large = || {
+        anyhow!(
+            "Image of {}x{} pixels is larger than {} bytes",
+            ihdr.width,
+            ihdr.height,
+            MAX_IMAGE_SIZE
+        )
+    };
+    let bits = ihdr.bits_per_pixel();
+    let width = ihdr.width as usize;
+    let height = ihdr.height as usize;
+
+    let mut len: usize = 0;
+    for (_, _, _, _, pass_width, pass_height) in passes(ihdr.interlaced, width, height) {
+        let pass_stride = pass_width
+            .checked_mul(bits)
+            .ok_or_else(too_large)?
+            .div_ceil(8);
+        let pass_len = (pass_stride + 1)
+            .checked_mul(pass_height)
+            .ok_or_else(too_large)?;
+        len = len.checked_add(pass_len).ok_or_else(too_large)?;
+    }
+    // The filtered data is never smaller than the decoded image
+    ensure!(len <= MAX_IMAGE_SIZE, too_large());
+
+    Ok(len)
+}
+
+/// Distance in bytes to the corresponding byte of the previous pixel, at least one
+fn filter_bpp(bits_per_pixel: usize) -> usize {
+    (bits_per_pixel / 8).max(1)
+}
+
+/// Copies the pixel at column `from` of `source` to column `to` of `target`
+fn copy_pixel(source: &[u8], from: usize, target: &mut [u8], to: usize, bits: usize) {
+    if bits >= 8 {
+        let size = bits / 8;
+        target[to * size..(to + 1) * size].copy_from_slice(&source[from * size..(from + 1) * size]);
+        return;
+    }
+
+    let per_byte = 8 / bits;
+    let mask = ((1u16 << bits) - 1) as u8;
+    let value = (source[from / per_byte] >> (8 - bits - (from % per_byte) * bits)) & mask;
+    let shift = 8 - bits - (to % per_byte) * bits;
+    target[to / per_byte] = (target[to / per_byte] & !(mask << shift)) | (value << shift);
+}
+
+fn paeth(a: u8, b: u8, c: u8) -> u8 {
+    let p = a as i16 + b as i16 - c as i16;
+    let pa = (p - a as i16).abs();
+    let pb = (p - b as i16).abs();
+    let pc = (p - c as i16).abs();
+
+    if pa <= pb && pa <= pc {
+        a
+    } else if pb <= pc {
+        b
+    } else {
+        c
+    }
+}
+
+/// Predicts a byte from its left (a), upper (b) and upper left (c) neighbours
+fn predict(filter: FilterType, a: u8, b: u8, c: u8) -> u8 {
+    match filter {
+        FilterType::None => 0,
+        FilterType::Sub => a,
+        FilterType::Up => b,
+        FilterType::Average => ((a as u16 + b as u16) / 2) as u8,
+        FilterType::Paeth => paeth(a, b, c),
+    }
+}
+
+fn unfilter(filter: FilterType, line: &mut [u8], prev: &[u8], bpp: usize) {
+    for i in 0..line.len() {
+        let a = if i >= bpp { line[i - bpp] } else { 0 };
+        let c = if i >= bpp { prev[i - bpp] } else { 0 };
+        line[i] = line[i].wrapping_add(predict(filter, a, prev[i], c));
+    }
+}
+
+fn apply_filter(filter: FilterType, line: &[u8], prev: &[u8], bpp: usize) -> Vec<u8> {
+    (0..line.len())
+        .map(|i| {
+            let a = if i >= bpp { line[i - bpp] } else { 0 };
+            let c = if i >= bpp { prev[i - bpp] } else { 0 };
+            line[i].wrapping_sub(predict(filter, a, prev[i], c))
+        })
+        .collect()
+}
+
+/// Appends the filter type byte and the filtered scanline to `out`
+fn filter_line(strategy: FilterStrategy, line: &[u8], prev: &[u8], bpp: usize, out: &mut Vec<u8>) {
+    let (filter, filtered) = match strategy {
+        FilterStrategy::Fixed(filter) => (filter, apply_filter(filter, line, prev, bpp)),
+        FilterStrategy::Adaptive => FilterType::ALL
+            .iter()
+            .map(|&filter| (filter, apply_filter(filter, line, prev, bpp)))
+            .min_by_key(|(_, filtered)| {
+                filtered
+                    .iter()
+                    .map(|&byte| (byte as i8).unsigned_abs() as u64)
+                    .sum::<u64>()
+            })
+            .expect("There is always a filter type to choose"),
+    };
+
+    out.push(filter as u8);
+    out.extend(filtered);
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::png::tests::PNG_FILE;
+    use crate::png::Png;
+
+    use super::*;
+
+    fn testing_image(width: u32, height: u32, bit_depth: u8, color_type: ColorType) -> RawImage {
+        let ihdr = Ihdr {
+            width,
+            height,
+            bit_depth,
+            color_type,
+            interlaced: false,
+        };
+        let stride = ihdr.stride(width as usize);
+        let mut data: Vec<u8> = (0..stride * height as usize)
+            .map(|i| (i * 37 % 251) as u8)
+            .collect();
+
+        // Padding bits at the end of a scanline are not preserved by interlacing
+        let padding = stride * 8 - width as usize * ihdr.bits_per_pixel();
+        for row in data.chunks_mut(stride) {
+            row[stride - 1] &= 0xFF << padding;
+        }
+
+        RawImage {
+            width,
+            height,
+            bit_depth,
+            color_type,
+            data,
+        }
+    }
+
+    fn round_trip(image: &RawImage, options: &EncodeOptions) -> RawImage {
+        let compressed = image.encode(options).unwrap();
+        RawImage::decode(&image.ihdr(options.interlaced), &compressed).unwrap()
+    }
+
+    #[test]
+    fn test_ihdr_from_png_file() {
+        let png = Png::try_from(&PNG_FILE[..]).unwrap();
+        let ihdr = png.ihdr().unwrap();
+        assert_eq!(ihdr.width, 50);
+        assert_eq!(ihdr.height, 50);
+        assert_eq!(ihdr.bit_depth, 8);
+        assert_eq!(ihdr.color_type, ColorType::Rgba);
+        assert!(!ihdr.interlaced);
+    }
+
+    #[test]
+    fn test_ihdr_as_chunk() {
+        let png = Png::try_from(&PNG_FILE[..]).unwrap();
+        let chunk = png.chunk_by_type("IHDR").unwrap();
+        assert_eq!(png.ihdr().unwrap().as_chunk().as_bytes(), chunk.as_bytes());
+    }
+
+    #[test]
+    fn test_invalid_bit_depth() {
+        let mut chunk = testing_image(1, 1, 8, ColorType::Rgb)
+            .ihdr(false)
+            .as_chunk();
+        chunk.data[8] = 4;
+        assert!(Ihdr::try_from(&chunk).is_err());
+    }
+
+    #[test]
+    fn test_hostile_size() {
+        let ihdr = Ihdr {
+            width: 0x7fffffff,
+            height: 0x7fffffff,
+            bit_depth: 16,
+            color_type: ColorType::Rgba,
+            interlaced: false,
+        };
+        let compressed = testing_image(1, 1, 8, ColorType::Rgb)
+            .encode(&EncodeOptions::default())
+            .unwrap();
+        assert!(RawImage::decode(&ihdr, &compressed).is_err());
+        assert!(RawImage::decode(
+            &Ihdr {
+                interlaced: true,
+                ..ihdr
+            },
+            &compressed
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_inflation_cap() {
+        let image = testing_image(4, 4, 8, ColorType::Rgb);
+        let mut ihdr = image.ihdr(false);
+        let compressed = image.encode(&EncodeOptions::default()).unwrap();
+        ihdr.height = 2;
+        assert!(RawImage::decode(&ihdr, &compressed).is_err());
+    }
+
+    #[test]
+    fn test_decode_png_file() {
+        let png = Png::try_from(&PNG_FILE[..]).unwrap();
+        let image = png.raw_image().unwrap();
+        assert_eq!(image.data.len(), 50 * 50 * 4);
+    }
+
+    #[test]
+    fn test_round_trip_png_file() {
+        let png = Png::try_from(&PNG_FILE[..]).unwrap();
+        let image = png.raw_image().unwrap();
+        assert_eq!(round_trip(&image, &EncodeOptions::default()), image);
+    }
+
+    #[test]
+    fn test_round_trip_every_filter() {
+        let image = testing_image(17, 9, 16, ColorType::Rgb);
+        for filter in ["none", "sub", "up", "average", "paeth", "adaptive"] {
+            let options = EncodeOptions {
+                filter: FilterStrategy::from_str(filter).unwrap(),
+                ..EncodeOptions::default()
+            };
+            assert_eq!(round_trip(&image, &options), image);
+        }
+    }
+
+    #[test]
+    fn test_round_trip_adam7() {
+        let options = EncodeOptions {
+            interlaced: true,
+            ..EncodeOptions::default()
+        };
+
+        for (width, height) in [(1, 1), (3, 2), (13, 7), (33, 17)] {
+            for (bit_depth, color_type) in [
+                (1, ColorType::Grayscale),
+                (4, ColorType::Indexed),
+                (8, ColorType::GrayscaleAlpha),
+                (16, ColorType::Rgba),
+            ] {
+                let image = testing_image(width, height, bit_depth, color_type);
+                assert_eq!(round_trip(&image, &options), image);
+            }
+        }
+    }
+
+    #[test]
+    fn test_invalid_compression_level() {
+        let image = testing_image(2, 2, 8, ColorType::Rgb);
+        let options = EncodeOptions {
+            level: 10,
+            ..EncodeOptions::default()
+        };
+        assert!(image.encode(&options).is_err());
+    }
+
+    #[test]
+    fn test_truncated_image_data() {
+        let image = testing_image(8, 8, 8, ColorType::Rgb);
+        let mut small = image.clone();
+        small.height = 4;
+        small.data.truncate(8 * 3 * 4);
+        let compressed = small.encode(&EncodeOptions::default()).unwrap();
+        assert!(RawImage::decode(&image.ihdr(false), &compressed).is_err());
+    }
+
+    #[test]
+    fn test_replace_image() {
+        let mut png = Png::try_from(&PNG_FILE[..]).unwrap();
+        let image = png.raw_image().unwrap();
+        let options = EncodeOptions {
+            filter: FilterStrategy::Fixed(FilterType::Paeth),
+            interlaced: true,
+            level: 9,
+            idat_size: 1000,
+        };
+        png.replace_image(&image, &options).unwrap();
+
+        let types: Vec<String> = png.chunks.iter().map(|c| c.r#type.to_string()).collect();
+        assert_eq!(types[..4], ["IHDR", "sRGB", "gAMA", "pHYs"]);
+        assert_eq!(types[types.len() - 2..], ["RuSt", "IEND"]);
+        assert!(png
+            .chunks
+            .iter()
+            .filter(|c| c.r#type == ChunkType::IDAT)
+            .all(|c| c.data.len() <= 1000));
+        assert!(png.ihdr().unwrap().interlaced);
+        assert_eq!(png.raw_image().unwrap(), image);
+    }
+}
@@ -0,0 +1,268 @@
+use std::fmt;
+use std::fmt::{Display, Formatter};
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use anyhow::{anyhow, ensure, Context, Error, Result};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use serde::{Deserialize, Serialize};
+
+use crate::crypto;
+use crate::recipient::{Identity, Recipient};
+use crate::signature::{Signer, Verifier};
+use crate::util::key_lines;
+
+/// Named public keys, and secret keys encrypted under a passphrase
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Keyring {
+    keys: Vec<Entry>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Entry {
+    pub name: String,
+    /// Either a `pngme1...` recipient or a `pngmesig1...` verifier
+    pub public_key: String,
+    /// Secret key encrypted with [`crypto::encrypt`], in base64
+    secret_key: Option<String>,
+}
+
+impl Entry {
+    fn is_signing(&self) -> bool {
+        Verifier::from_str(&self.public_key).is_ok()
+    }
+
+    /// Decrypts the secret key with the passphrase it was added with
+    fn secret_key(&self, passphrase: &str) -> Result<String, Error> {
+        let encrypted = self
+            .secret_key
+            .as_ref()
+            .with_context(|| format!("Key {} has no secret key", self.name))?;
+        let secret = crypto::decrypt(&STANDARD.decode(encrypted)?, passphrase)
+            .map_err(|_| anyhow!("Wrong passphrase for key {}", self.name))?;
+
+        Ok(String::from_utf8(secret)?)
+    }
+
+    /// Contents of a key file, the secret key with the public key as a comment
+    pub fn export_secret(&self, passphrase: &str) -> Result<String, Error> {
+        Ok(format!(
+            "# public key: {}\n{}\n",
+            self.public_key,
+            self.secret_key(passphrase)?
+        ))
+    }
+}
+
+impl Display for Entry {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let usage = if self.is_signing() {
+            "signing"
+        } else {
+            "encryption"
+        };
+        let secret = if self.secret_key.is_some() {
+            "secret"
+        } else {
+            "public"
+        };
+
+        write!(
+            f,
+            "{:<16}{:<12}{:<8}{}",
+            self.name, usage, secret, self.public_key
+        )
+    }
+}
+
+impl Keyring {
+    /// `pngme/keyring.json` in the user's config directory, `$XDG_CONFIG_HOME` on Linux
+    pub fn default_path() -> Result<PathBuf, Error> {
+        Ok(dirs::config_dir()
+            .context("Cannot find the config directory")?
+            .join("pngme")
+            .join("keyring.json"))
+    }
+
+    /// Reads the keyring, which is empty if the file does not exist yet
+    pub fn load(path: &Path) -> Result<Keyring, Error> {
+        if !path.exists() {
+            return Ok(Keyring::default());
+        }
+
+        let text = fs::read_to_string(path)
+            .with_context(|| format!("Cannot read keyring {}", path.display()))?;
+        serde_json::from_str(&text).context("Keyring is corrupted")
+    }
+
+    /// Writes the keyring, readable only by the user on Unix
+    pub fn save(&self, path: &Path) -> Result<(), Error> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+
+        let mut options = fs::OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        options
+            .open(path)?
+            .write_all(serde_json::to_string_pretty(self)?.as_bytes())?;
+
+        Ok(())
+    }
+
+    pub fn entries(&self) -> &[Entry] {
+        &self.keys
+    }
+
+    pub fn entry(&self, name: &str) -> Result<&Entry, Error> {
+        self.keys
+            .iter()
+            .find(|entry| entry.name == name)
+            .with_context(|| format!("No key named {} in the keyring", name))
+    }
+
+    fn insert(&mut self, entry: Entry) -> Result<(), Error> {
+        ensure!(
+            !entry.name.is_empty() && !entry.name.contains(char::is_whitespace),
+            "Key names cannot be empty or contain spaces"
+        );
+        ensure!(
+            self.entry(&entry.name).is_err(),
+            "Keyring already has a key named {}",
+            entry.name
+        );
+
+        self.keys.push(entry);
+        Ok(())
+    }
+
+    /// Adds a `pngme1...` recipient or a `pngmesig1...` verifier
+    pub fn add_public(&mut self, name: &str, public_key: &str) -> Result<(), Error> {
+        ensure!(
+            Recipient::from_str(public_key).is_ok() || Verifier::from_str(public_key).is_ok(),
+            "{} is not a pngme public key",
+            public_key
+        );
+
+        self.insert(Entry {
+            name: name.to_string(),
+            public_key: public_key.to_string(),
+            secret_key: None,
+        })
+    }
+
+    /// Adds the identity or signing key of a key file, encrypted under the passphrase
+    pub fn add_secret(
+        &mut self,
+        name: &str,
+        key_file: &str,
+        passphrase: &str,
+    ) -> Result<(), Error> {
+        let secret_key = key_lines(key_file)
+            .next()
+            .context("Key file holds no key")?;
+        let public_key = match (Identity::from_str(secret_key), Signer::from_str(secret_key)) {
+            (Ok(identity), _) => identity.recipient().to_string(),
+            (_, Ok(signer)) => signer.verifier().to_string(),
+            _ => return Err(anyhow!("Key file holds no pngme secret key")),
+        };
+        let encrypted = crypto::encrypt(secret_key.as_bytes(), passphrase)?;
+
+        self.insert(Entry {
+            name: name.to_string(),
+            public_key,
+            secret_key: Some(STANDARD.encode(encrypted)),
+        })
+    }
+
+    pub fn remove(&mut self, name: &str) -> Result<Entry, Error> {
+        let index = self
+            .keys
+            .iter()
+            .position(|entry| entry.name == name)
+            .with_context(|| format!("No key named {} in the keyring", name))?;
+
+        Ok(self.keys.remove(index))
+    }
+
+    pub fn recipient(&self, name: &str) -> Result<Recipient, Error> {
+        Recipient::from_str(&self.entry(name)?.public_key)
+            .with_context(|| format!("Key {} is not an encryption key", name))
+    }
+
+    pub fn verifier(&self, name: &str) -> Result<Verifier, Error> {
+        Verifier::from_str(&self.entry(name)?.public_key)
+            .with_context(|| format!("Key {} is not a signing key", name))
+    }
+
+    pub fn identity(&self, name: &str, passphrase: &str) -> Result<Identity, Error> {
+        let entry = self.entry(name)?;
+        ensure!(!entry.is_signing(), "Key {} is not an encryption key", name);
+
+        Identity::from_str(&entry.secret_key(passphrase)?)
+    }
+
+    pub fn signer(&self, name: &str, passphrase: &str) -> Result<Signer, Error> {
+        let entry = self.entry(name)?;
+        ensure!(entry.is_signing(), "Key {} is not a signing key", name);
+
+        Signer::from_str(&entry.secret_key(passphrase)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_public_keys() {
+        let mut keyring = Keyring::default();
+        let alice = Identity::generate().recipient();
+        keyring.add_public("alice", &alice.to_string()).unwrap();
+
+        assert_eq!(keyring.recipient("alice").unwrap(), alice);
+        assert!(keyring.add_public("alice", &alice.to_string()).is_err());
+        assert!(keyring.add_public("bob", "pngme1invalid").is_err());
+        assert!(keyring.add_public("two words", &alice.to_string()).is_err());
+        assert!(keyring.recipient("bob").is_err());
+
+        keyring.remove("alice").unwrap();
+        assert!(keyring.entries().is_empty());
+    }
+
+    #[test]
+    fn test_secret_keys() {
+        let mut keyring = Keyring::default();
+        let identity = Identity::generate();
+        let signer = Signer::generate();
+        keyring
+            .add_secret("me", &identity.to_file_string(), "passphrase")
+            .unwrap();
+        keyring
+            .add_secret("work", &signer.to_file_string(), "passphrase")
+            .unwrap();
+
+        let json = serde_json::to_string(&keyring).unwrap();
+        assert!(!json.contains(&identity.to_string()));
+        let keyring: Keyring = serde_json::from_str(&json).unwrap();
+
+        assert_eq!(keyring.recipient("me").unwrap(), identity.recipient());
+        assert_eq!(
+            keyring.identity("me", "passphrase").unwrap().recipient(),
+            identity.recipient()
+        );
+        assert_eq!(
+            keyring.signer("work", "passphrase").unwrap().verifier(),
+            signer.verifier()
+        );
+        assert!(keyring.identity("me", "wrong").is_err());
+        assert!(keyring.signer("me", "passphrase").is_err());
+        assert_eq!(keyring.verifier("work").unwrap(), signer.verifier());
+        assert!(keyring.verifier("me").is_err());
+    }
+}
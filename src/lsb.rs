@@ -0,0 +1,840 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+
+use anyhow::{anyhow, bail, ensure, Error, Result};
+use argon2::Argon2;
+use clap::ValueEnum;
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha20Rng;
+
+use crate::image::{ColorType, RawImage};
+
+/// Size of the header that is embedded before the payload
+///
+/// The header is written one bit per sample into the first selected samples of the eligible
+/// pixels, so it leaves the same samples untouched as the payload. Extraction tries every alpha
+/// mode and set of channels until the header it reads names them. Its first byte holds the
+/// version, the alpha mode and the bits per sample, the second byte the channel mask and the
+/// rest the payload length.
+pub const HEADER_SIZE: usize = 6;
+
+const VERSION: u8 = 1;
+
+const ORDER_SALT: &[u8] = b"pngme lsb embedding order";
+
+/// Selects which pixels carry the payload by their transparency
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum AlphaMode {
+    /// Use every pixel
+    Any,
+    /// Leave fully transparent pixels untouched
+    SkipTransparent,
+    /// Only use fully transparent pixels
+    OnlyTransparent,
+}
+
+impl Display for AlphaMode {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            AlphaMode::Any => "any",
+            AlphaMode::SkipTransparent => "skip-transparent",
+            AlphaMode::OnlyTransparent => "only-transparent",
+        };
+
+        write!(f, "{}", name)
+    }
+}
+
+/// Set of channels to embed in, a bit per channel
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Channels(u8);
+
+impl Channels {
+    pub const RED: Channels = Channels(0b00001);
+    pub const GREEN: Channels = Channels(0b00010);
+    pub const BLUE: Channels = Channels(0b00100);
+    pub const ALPHA: Channels = Channels(0b01000);
+    pub const GRAY: Channels = Channels(0b10000);
+    pub const ALL: Channels = Channels(0b11111);
+
+    /// Channel at `index` of a pixel of the given color type
+    fn at(color_type: ColorType, index: usize) -> Channels {
+        match (color_type, index) {
+            (ColorType::Grayscale | ColorType::GrayscaleAlpha, 0) => Channels::GRAY,
+            (ColorType::Rgb | ColorType::Rgba, 0) => Channels::RED,
+            (ColorType::Rgb | ColorType::Rgba, 1) => Channels::GREEN,
+            (ColorType::Rgb | ColorType::Rgba, 2) => Channels::BLUE,
+            _ => Channels::ALPHA,
+        }
+    }
+
+    /// Whether the channel at `index` of a pixel of the given color type is in the set
+    fn contains(&self, color_type: ColorType, index: usize) -> bool {
+        self.0 & Channels::at(color_type, index).0 != 0
+    }
+}
+
+impl FromStr for Channels {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Error> {
+        if s == "all" {
+            return Ok(Channels::ALL);
+        }
+
+        s.chars().try_fold(Channels(0), |channels, c| {
+            let channel = match c.to_ascii_lowercase() {
+                'r' => Channels::RED,
+                'g' => Channels::GREEN,
+                'b' => Channels::BLUE,
+                'a' => Channels::ALPHA,
+                'y' => Channels::GRAY,
+                _ => bail!("Invalid channel {}, use r, g, b, a, y (gray) or all", c),
+            };
+
+            Ok(Channels(channels.0 | channel.0))
+        })
+    }
+}
+
+impl Display for Channels {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        if *self == Channels::ALL {
+            return write!(f, "all");
+        }
+
+        for (channel, name) in [
+            (Channels::RED, 'r'),
+            (Channels::GREEN, 'g'),
+            (Channels::BLUE, 'b'),
+            (Channels::ALPHA, 'a'),
+            (Channels::GRAY, 'y'),
+        ] {
+            if self.0 & channel.0 != 0 {
+                write!(f, "{}", name)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct LsbOptions {
+    /// Number of low bits of every sample that carry the payload, between 1 and 4
+    pub bits: u8,
+    pub channels: Channels,
+    /// The alpha channel never carries the payload unless the mode is [`AlphaMode::Any`], since
+    /// changing it could change which pixels are selected
+    pub alpha: AlphaMode,
+}
+
+impl Default for LsbOptions {
+    fn default() -> Self {
+        LsbOptions {
+            bits: 1,
+            channels: Channels::ALL,
+            alpha: AlphaMode::Any,
+        }
+    }
+}
+
+impl LsbOptions {
+    fn header(&self, length: usize) -> Vec<u8> {
+        [
+            (VERSION << 4) | ((self.alpha as u8) << 2) | (self.bits - 1),
+            self.channels.0,
+        ]
+        .iter()
+        .chain((length as u32).to_be_bytes().iter())
+        .copied()
+        .collect()
+    }
+
+    /// Parses a header, returning the options and the payload length
+    fn from_header(header: &[u8]) -> Result<(LsbOptions, usize), Error> {
+        ensure!(header[0] >> 4 == VERSION, "No LSB payload found");
+
+        let alpha = match (header[0] >> 2) & 0b11 {
+            0 => AlphaMode::Any,
+            1 => AlphaMode::SkipTransparent,
+            2 => AlphaMode::OnlyTransparent,
+            _ => bail!("No LSB payload found"),
+        };
+        let options = LsbOptions {
+            bits: (header[0] & 0b11) + 1,
+            channels: Channels(header[1]),
+            alpha,
+        };
+        let length = u32::from_be_bytes(header[2..6].try_into()?) as usize;
+
+        Ok((options, length))
+    }
+}
+
+/// A bit of the image data, the index of its byte and its position in that byte
+type Slot = (usize, u8);
+
+/// Seed of the pseudorandom embedding order
+pub type Key = [u8; 32];
+
+/// Size in bytes of a sample and the number of samples in a pixel
+fn sample_layout(image: &RawImage) -> Result<(usize, usize), Error> {
+    ensure!(
+        image.color_type != ColorType::Indexed,
+        "LSB embedding is not supported for indexed images"
+    );
+    ensure!(
+        image.bit_depth == 8 || image.bit_depth == 16,
+        "LSB embedding needs a bit depth of 8 or 16, image has a bit depth of {}",
+        image.bit_depth
+    );
+
+    Ok((image.bit_depth as usize / 8, image.color_type.channels()))
+}
+
+/// Channels of the image that carry data, without alpha unless every pixel is used
+fn selected_channels(image: &RawImage, options: &LsbOptions) -> Result<Channels, Error> {
+    let (_, channels) = sample_layout(image)?;
+    let present = (0..channels).fold(0, |mask, index| {
+        mask | Channels::at(image.color_type, index).0
+    });
+    let selected = match options.alpha {
+        AlphaMode::Any => present & options.channels.0,
+        _ => {
+            ensure!(
+                present & Channels::ALPHA.0 != 0,
+                "Pixels can only be selected by transparency in images with an alpha channel"
+            );
+            present & options.channels.0 & !Channels::ALPHA.0
+        }
+    };
+    ensure!(
+        selected != 0,
+        "None of the selected channels are in the image"
+    );
+
+    Ok(Channels(selected))
+}
+
+/// Every alpha mode and set of channels a payload can be embedded with in the image
+fn candidates(image: &RawImage) -> Vec<LsbOptions> {
+    [
+        AlphaMode::Any,
+        AlphaMode::SkipTransparent,
+        AlphaMode::OnlyTransparent,
+    ]
+    .into_iter()
+    .flat_map(|alpha| {
+        (1..=Channels::ALL.0).map(move |mask| LsbOptions {
+            bits: 1,
+            channels: Channels(mask),
+            alpha,
+        })
+    })
+    .filter(|options| {
+        selected_channels(image, options).is_ok_and(|selected| selected == options.channels)
+    })
+    .collect()
+}
+
+/// Derives the key that seeds the embedding order from a password
+///
+/// The salt is fixed because the order has to be known before anything can be read from the
+/// image, so the work factor of Argon2id is what slows down guessing.
+pub fn derive_key(password: &str) -> Result<Key, Error> {
+    let mut key = [0; 32];
+    Argon2::default()
+        .hash_password_into(password.as_bytes(), ORDER_SALT, &mut key)
+        .map_err(|error| anyhow!("Cannot derive key from password: {}", error))?;
+
+    Ok(key)
+}
+
+/// Whether the pixel carries data in the alpha mode, by whether it is fully transparent
+fn eligible(image: &RawImage, alpha: AlphaMode, pixel: usize) -> bool {
+    let size = image.bit_depth as usize / 8;
+    let end = (pixel + 1) * size * image.color_type.channels();
+    let transparent = || image.data[end - size..end].iter().all(|&byte| byte == 0);
+
+    match alpha {
+        AlphaMode::Any => true,
+        AlphaMode::SkipTransparent => !transparent(),
+        AlphaMode::OnlyTransparent => transparent(),
+    }
+}
+
+/// Indices of the selected channels in a pixel
+fn channel_indices(image: &RawImage, options: &LsbOptions) -> Result<Vec<usize>, Error> {
+    let (_, channels) = sample_layout(image)?;
+    let selected = selected_channels(image, options)?;
+
+    Ok((0..channels)
+        .filter(|&channel| selected.contains(image.color_type, channel))
+        .collect())
+}
+
+/// Number of selected samples of the eligible pixels, counted without listing them
+fn sample_count(image: &RawImage, options: &LsbOptions) -> Result<usize, Error> {
+    let channels = channel_indices(image, options)?.len();
+    let pixels = image.width as usize * image.height as usize;
+    let eligible = match options.alpha {
+        AlphaMode::Any => pixels,
+        alpha => (0..pixels)
+            .filter(|&pixel| eligible(image, alpha, pixel))
+            .count(),
+    };
+
+    Ok(eligible * channels)
+}
+
+/// Selected samples of the eligible pixels, addressed by their index in pixel order
+struct Samples {
+    size: usize,
+    channels: usize,
+    selected: Vec<usize>,
+    /// Eligible pixels, unless every pixel is
+    pixels: Option<Vec<u32>>,
+    len: usize,
+}
+
+impl Samples {
+    fn new(image: &RawImage, options: &LsbOptions) -> Result<Samples, Error> {
+        let (size, channels) = sample_layout(image)?;
+        let selected = channel_indices(image, options)?;
+        let count = image.width as usize * image.height as usize;
+        let pixels = (options.alpha != AlphaMode::Any).then(|| {
+            (0..count)
+                .filter(|&pixel| eligible(image, options.alpha, pixel))
+                .map(|pixel| pixel as u32)
+                .collect::<Vec<u32>>()
+        });
+        let len = pixels.as_ref().map_or(count, Vec::len) * selected.len();
+
+        Ok(Samples {
+            size,
+            channels,
+            selected,
+            pixels,
+            len,
+        })
+    }
+
+    /// Index of the low byte of the sample
+    fn byte(&self, index: usize) -> usize {
+        let pixel = index / self.selected.len();
+        let pixel = self
+            .pixels
+            .as_ref()
+            .map_or(pixel, |pixels| pixels[pixel] as usize);
+
+        (pixel * self.channels + self.selected[index % self.selected.len()] + 1) * self.size - 1
+    }
+}
+
+/// Fisher-Yates shuffle of `0..len` that only stores the positions it swapped, so drawing a few
+/// elements of a huge range takes memory for those elements only
+struct SparseShuffle {
+    len: usize,
+    drawn: usize,
+    swapped: HashMap<usize, usize>,
+}
+
+impl SparseShuffle {
+    fn new(len: usize) -> SparseShuffle {
+        SparseShuffle {
+            len,
+            drawn: 0,
+            swapped: HashMap::new(),
+        }
+    }
+
+    /// Element at a position that was not drawn yet
+    fn get(&self, position: usize) -> usize {
+        self.swapped.get(&position).copied().unwrap_or(position)
+    }
+
+    /// Swaps a random element of the rest into the next position and returns it
+    fn draw(&mut self, rng: &mut ChaCha20Rng) -> usize {
+        let position = rng.gen_range(self.drawn..self.len);
+        let element = self.get(position);
+        self.swapped.insert(position, self.get(self.drawn));
+        self.swapped.remove(&self.drawn);
+        self.drawn += 1;
+
+        element
+    }
+}
+
+/// Shuffles the samples with the key as far as the header, returning the header samples
+fn shuffle_header(len: usize, key: &Key) -> (SparseShuffle, Vec<usize>) {
+    let mut rng = ChaCha20Rng::from_seed(*key);
+    let mut order = SparseShuffle::new(len);
+    let header = (0..HEADER_SIZE * 8).map(|_| order.draw(&mut rng)).collect();
+
+    (order, header)
+}
+
+/// Slots of the header, the low bits of the first samples in pixel order or in the key order
+fn header_slots(samples: &Samples, key: Option<&Key>) -> Result<Vec<Slot>, Error> {
+    ensure!(
+        samples.len >= HEADER_SIZE * 8,
+        "Image is too small to hold an LSB payload"
+    );
+    let header = match key {
+        Some(key) => shuffle_header(samples.len, key).1,
+        None => (0..HEADER_SIZE * 8).collect(),
+    };
+
+    Ok(header
+        .into_iter()
+        .map(|sample| (samples.byte(sample), 0))
+        .collect())
+}
+
+/// Slots of a payload of `len` bytes in the samples after the header, made as they are used
+fn payload_slots(
+    samples: Samples,
+    bits: u8,
+    key: Option<&Key>,
+    len: usize,
+) -> Box<dyn Iterator<Item = Slot>> {
+    let bits = bits as usize;
+    let first = HEADER_SIZE * 8;
+    let count = samples.len;
+    let slot =
+        move |sample: usize, slot: usize| (samples.byte(sample), (bits - 1 - slot % bits) as u8);
+
+    match key {
+        None => Box::new((0..len * 8).map(move |i| slot(first + i / bits, i))),
+        // Samples and their bits are shuffled too, so consecutive payload bits do not end up in
+        // the same pixel
+        Some(key) => {
+            let (order, _) = shuffle_header(count, key);
+            let mut rng = ChaCha20Rng::from_seed(*key);
+            rng.set_stream(1);
+            let mut slots = SparseShuffle::new((count - first) * bits);
+
+            Box::new((0..len * 8).map(move |_| {
+                let i = slots.draw(&mut rng);
+                slot(order.get(first + i / bits), i)
+            }))
+        }
+    }
+}
+
+/// Maximum payload size in bytes that fits in the image with the given options
+pub fn capacity(image: &RawImage, options: &LsbOptions) -> Result<usize, Error> {
+    ensure!(
+        (1..=4).contains(&options.bits),
+        "Invalid number of bits per sample, it must be between 1 and 4"
+    );
+    let count = sample_count(image, options)?;
+    ensure!(
+        count >= HEADER_SIZE * 8,
+        "Image is too small to hold an LSB payload"
+    );
+
+    Ok((count - HEADER_SIZE * 8) * options.bits as usize / 8)
+}
+
+/// Writes the header and the payload into the low bits of the samples
+///
+/// With a key, the header and the payload are scattered over the image in an order that can
+/// only be reproduced with the same key.
+pub fn embed(
+    image: &mut RawImage,
+    payload: &[u8],
+    options: &LsbOptions,
+    key: Option<&Key>,
+) -> Result<(), Error> {
+    let capacity = capacity(image, options)?;
+    ensure!(
+        payload.len() <= capacity,
+        "Payload is too long, the image can hold at most {} bytes with these options",
+        capacity
+    );
+
+    let samples = Samples::new(image, options)?;
+    let header = LsbOptions {
+        channels: selected_channels(image, options)?,
+        ..*options
+    }
+    .header(payload.len());
+    write_bytes(image, header_slots(&samples, key)?, &header);
+    write_bytes(
+        image,
+        payload_slots(samples, options.bits, key, payload.len()),
+        payload,
+    );
+
+    Ok(())
+}
+
+/// Reads a payload written by [`embed`] with the same key, the options are taken from the header
+pub fn extract(image: &RawImage, key: Option<&Key>) -> Result<Vec<u8>, Error> {
+    for candidate in candidates(image) {
+        let Ok(samples) = Samples::new(image, &candidate) else {
+            continue;
+        };
+        let Ok(slots) = header_slots(&samples, key) else {
+            continue;
+        };
+        let Ok((options, length)) = LsbOptions::from_header(&read_bytes(image, slots)) else {
+            continue;
+        };
+        if options.alpha != candidate.alpha || options.channels != candidate.channels {
+            continue;
+        }
+
+        // Samples of another candidate can pass for a header by chance, with any length
+        if capacity(image, &options).map_or(true, |capacity| length > capacity) {
+            continue;
+        }
+        return Ok(read_bytes(
+            image,
+            payload_slots(samples, options.bits, key, length),
+        ));
+    }
+
+    bail!("No LSB payload found")
+}
+
+/// Writes the bytes into the slots, most significant bit first
+fn write_bytes(image: &mut RawImage, slots: impl IntoIterator<Item = Slot>, bytes: &[u8]) {
+    for (i, (byte, bit)) in slots.into_iter().take(bytes.len() * 8).enumerate() {
+        let value = (bytes[i / 8] >> (7 - i % 8)) & 1;
+        image.data[byte] = (image.data[byte] & !(1 << bit)) | (value << bit);
+    }
+}
+
+/// Packs the bits of the slots into bytes, most significant bit first
+fn read_bytes(image: &RawImage, slots: impl IntoIterator<Item = Slot>) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    for (i, (byte, bit)) in slots.into_iter().enumerate() {
+        if i % 8 == 0 {
+            bytes.push(0);
+        }
+        let value = bytes.last_mut().unwrap();
+        *value = (*value << 1) | ((image.data[byte] >> bit) & 1);
+    }
+
+    bytes
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::image::EncodeOptions;
+    use crate::png::tests::PNG_FILE;
+    use crate::png::Png;
+
+    use super::*;
+
+    fn testing_image(bit_depth: u8, color_type: ColorType) -> RawImage {
+        let len = 32 * 32 * color_type.channels() * bit_depth as usize / 8;
+
+        RawImage {
+            width: 32,
+            height: 32,
+            bit_depth,
+            color_type,
+            data: (0..len).map(|i| (i * 31 % 256) as u8).collect(),
+        }
+    }
+
+    /// RGBA image whose left half is fully transparent
+    fn transparent_image() -> RawImage {
+        let mut image = testing_image(8, ColorType::Rgba);
+        for (pixel, rgba) in image.data.chunks_mut(4).enumerate() {
+            rgba[3] = if pixel % 32 < 16 { 0 } else { 255 };
+        }
+        image
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let message = "This is where your secret message will be!".as_bytes();
+
+        for bit_depth in [8, 16] {
+            for color_type in [
+                ColorType::Grayscale,
+                ColorType::GrayscaleAlpha,
+                ColorType::Rgb,
+                ColorType::Rgba,
+            ] {
+                let mut image = testing_image(bit_depth, color_type);
+                embed(&mut image, message, &LsbOptions::default(), None).unwrap();
+                assert_eq!(extract(&image, None).unwrap(), message);
+            }
+        }
+    }
+
+    #[test]
+    fn test_round_trip_bits_per_sample() {
+        let message = [0xA5; 500];
+
+        for bits in 1..=4 {
+            let original = testing_image(8, ColorType::Rgb);
+            let mut image = original.clone();
+            let options = LsbOptions {
+                bits,
+                ..LsbOptions::default()
+            };
+            embed(&mut image, &message[..100 * bits as usize], &options, None).unwrap();
+            assert_eq!(
+                extract(&image, None).unwrap(),
+                &message[..100 * bits as usize]
+            );
+
+            let mask = 0xFF << bits;
+            assert!(original
+                .data
+                .iter()
+                .zip(image.data.iter())
+                .all(|(a, b)| a & mask == b & mask));
+        }
+    }
+
+    #[test]
+    fn test_channel_mask() {
+        let original = testing_image(16, ColorType::Rgb);
+        let mut image = original.clone();
+        let options = LsbOptions {
+            channels: Channels::from_str("b").unwrap(),
+            ..LsbOptions::default()
+        };
+        embed(&mut image, &[0xFF; 100], &options, None).unwrap();
+        assert_eq!(extract(&image, None).unwrap(), [0xFF; 100]);
+
+        for (i, (a, b)) in original.data.iter().zip(image.data.iter()).enumerate() {
+            if i % 6 != 5 {
+                assert_eq!(a, b);
+            }
+        }
+    }
+
+    #[test]
+    fn test_header_by_chance() {
+        let mut image = testing_image(8, ColorType::Rgb);
+        let options = LsbOptions {
+            channels: Channels::from_str("b").unwrap(),
+            ..LsbOptions::default()
+        };
+        embed(&mut image, &[0xFF; 100], &options, None).unwrap();
+
+        // The red samples, tried first, pass for a header with a length beyond the capacity
+        let red = LsbOptions {
+            channels: Channels::RED,
+            ..LsbOptions::default()
+        };
+        let slots = header_slots(&Samples::new(&image, &red).unwrap(), None).unwrap();
+        write_bytes(&mut image, slots, &red.header(u32::MAX as usize));
+
+        assert_eq!(extract(&image, None).unwrap(), [0xFF; 100]);
+    }
+
+    #[test]
+    fn test_skip_transparent() {
+        let original = transparent_image();
+        let mut image = original.clone();
+        let options = LsbOptions {
+            alpha: AlphaMode::SkipTransparent,
+            ..LsbOptions::default()
+        };
+        embed(&mut image, &[0; 150], &options, None).unwrap();
+        assert_eq!(extract(&image, None).unwrap(), [0; 150]);
+
+        for (pixel, (a, b)) in original
+            .data
+            .chunks(4)
+            .zip(image.data.chunks(4))
+            .enumerate()
+        {
+            if a[3] == 0 {
+                assert_eq!(a, b);
+            } else {
+                assert_eq!(a[3], b[3], "alpha of pixel {} changed", pixel);
+            }
+        }
+    }
+
+    #[test]
+    fn test_header_in_selected_samples() {
+        let original = transparent_image();
+        let mut image = original.clone();
+        let options = LsbOptions {
+            channels: Channels::BLUE,
+            alpha: AlphaMode::SkipTransparent,
+            ..LsbOptions::default()
+        };
+        embed(&mut image, &[0xFF; 40], &options, None).unwrap();
+        assert_eq!(extract(&image, None).unwrap(), [0xFF; 40]);
+
+        for (a, b) in original.data.chunks(4).zip(image.data.chunks(4)) {
+            if a[3] == 0 {
+                assert_eq!(a, b);
+            } else {
+                assert_eq!([a[0], a[1], a[3]], [b[0], b[1], b[3]]);
+            }
+        }
+    }
+
+    #[test]
+    fn test_only_transparent() {
+        let original = transparent_image();
+        let mut image = original.clone();
+        let options = LsbOptions {
+            bits: 4,
+            channels: Channels::ALL,
+            alpha: AlphaMode::OnlyTransparent,
+        };
+        let capacity = capacity(&image, &options).unwrap();
+        assert_eq!(capacity, (32 * 16 * 3 - HEADER_SIZE * 8) * 4 / 8);
+        embed(&mut image, &vec![7; capacity], &options, None).unwrap();
+        assert_eq!(extract(&image, None).unwrap(), vec![7; capacity]);
+
+        for (a, b) in original.data.chunks(4).zip(image.data.chunks(4)) {
+            if a[3] != 0 {
+                assert_eq!(a, b);
+            }
+        }
+    }
+
+    #[test]
+    fn test_only_transparent_without_alpha() {
+        let mut image = testing_image(8, ColorType::Rgb);
+        let options = LsbOptions {
+            alpha: AlphaMode::OnlyTransparent,
+            ..LsbOptions::default()
+        };
+        assert!(embed(&mut image, b"Message", &options, None).is_err());
+    }
+
+    #[test]
+    fn test_invalid_bits() {
+        let mut image = testing_image(8, ColorType::Rgb);
+        for bits in [0, 5] {
+            let options = LsbOptions {
+                bits,
+                ..LsbOptions::default()
+            };
+            assert!(embed(&mut image, b"Message", &options, None).is_err());
+        }
+    }
+
+    #[test]
+    fn test_channels_from_str() {
+        assert_eq!(Channels::from_str("all").unwrap(), Channels::ALL);
+        assert_eq!(
+            Channels::from_str("RGB").unwrap(),
+            Channels(Channels::RED.0 | Channels::GREEN.0 | Channels::BLUE.0)
+        );
+        assert!(Channels::from_str("rgbx").is_err());
+    }
+
+    #[test]
+    fn test_payload_too_long() {
+        let mut image = testing_image(8, ColorType::Grayscale);
+        let options = LsbOptions::default();
+        let capacity = capacity(&image, &options).unwrap();
+        assert_eq!(capacity, (32 * 32 - HEADER_SIZE * 8) / 8);
+        assert!(embed(&mut image, &vec![0; capacity], &options, None).is_ok());
+        assert!(embed(&mut image, &vec![0; capacity + 1], &options, None).is_err());
+    }
+
+    #[test]
+    fn test_indexed_image() {
+        let mut image = testing_image(8, ColorType::Indexed);
+        assert!(embed(&mut image, b"Message", &LsbOptions::default(), None).is_err());
+    }
+
+    #[test]
+    fn test_no_payload() {
+        let mut image = testing_image(8, ColorType::Rgb);
+        image.data.iter_mut().for_each(|sample| *sample |= 1);
+        assert!(extract(&image, None).is_err());
+    }
+
+    #[test]
+    fn test_derive_key() {
+        let key = derive_key("password").unwrap();
+        assert_eq!(key, derive_key("password").unwrap());
+        assert_ne!(key, derive_key("Password").unwrap());
+    }
+
+    #[test]
+    fn test_round_trip_with_key() {
+        let key = derive_key("password").unwrap();
+        let other = derive_key("another password").unwrap();
+        let original = testing_image(8, ColorType::Rgb);
+        let mut image = original.clone();
+        embed(&mut image, &[0xFF; 200], &LsbOptions::default(), Some(&key)).unwrap();
+
+        assert_eq!(extract(&image, Some(&key)).unwrap(), [0xFF; 200]);
+        assert_ne!(extract(&image, None).ok(), Some(vec![0xFF; 200]));
+        assert_ne!(extract(&image, Some(&other)).ok(), Some(vec![0xFF; 200]));
+
+        // Changed samples are spread over the image instead of filling it from the start
+        let changed: Vec<usize> = original
+            .data
+            .iter()
+            .zip(image.data.iter())
+            .enumerate()
+            .filter(|(_, (a, b))| a != b)
+            .map(|(i, _)| i)
+            .collect();
+        assert!(changed.iter().any(|&i| i > image.data.len() / 2));
+    }
+
+    #[test]
+    fn test_round_trip_with_key_skip_transparent() {
+        let key = derive_key("password").unwrap();
+        let original = transparent_image();
+        let mut image = original.clone();
+        let options = LsbOptions {
+            bits: 3,
+            alpha: AlphaMode::SkipTransparent,
+            ..LsbOptions::default()
+        };
+        let capacity = capacity(&image, &options).unwrap();
+        embed(&mut image, &vec![0x5A; capacity], &options, Some(&key)).unwrap();
+        assert_eq!(extract(&image, Some(&key)).unwrap(), vec![0x5A; capacity]);
+
+        for (a, b) in original.data.chunks(4).zip(image.data.chunks(4)) {
+            if a[3] == 0 {
+                assert_eq!(a, b);
+            }
+        }
+    }
+
+    #[test]
+    fn test_sparse_shuffle() {
+        let mut rng = ChaCha20Rng::from_seed([7; 32]);
+        let mut shuffle = SparseShuffle::new(1000);
+        let mut drawn: Vec<usize> = (0..1000).map(|_| shuffle.draw(&mut rng)).collect();
+        assert_ne!(drawn, (0..1000).collect::<Vec<usize>>());
+
+        drawn.sort();
+        assert_eq!(drawn, (0..1000).collect::<Vec<usize>>());
+    }
+
+    #[test]
+    fn test_round_trip_png_file() {
+        let mut png = Png::try_from(&PNG_FILE[..]).unwrap();
+        let mut image = png.raw_image().unwrap();
+        let options = LsbOptions {
+            bits: 2,
+            channels: Channels::from_str("rgb").unwrap(),
+            alpha: AlphaMode::SkipTransparent,
+        };
+        embed(&mut image, b"Hidden in the pixels", &options, None).unwrap();
+        png.replace_image(&image, &EncodeOptions::default())
+            .unwrap();
+
+        let png = Png::try_from(png.as_bytes().as_slice()).unwrap();
+        let image = png.raw_image().unwrap();
+        assert_eq!(extract(&image, None).unwrap(), b"Hidden in the pixels");
+    }
+}
@@ -0,0 +1,258 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+
+use anyhow::{anyhow, bail, ensure, Context, Error, Result};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+use crate::chunk::Chunk;
+use crate::chunk_type::ChunkType;
+use crate::png::Png;
+
+/// Private, ancillary and unsafe to copy, so editors that change the image drop it
+pub const CHUNK_TYPE: &str = "hmAC";
+
+/// Prefix of the tag over the whole list, so it differs from every chunk tag
+const LIST_DOMAIN: &[u8] = b"pngme-hmac-list";
+
+const TAG_SIZE: usize = 32;
+
+/// Chunk type, occurrence among chunks of that type and tag of one covered chunk
+const ENTRY_SIZE: usize = 4 + 2 + TAG_SIZE;
+
+/// Chunks that a tag covers
+#[derive(Clone, Debug, PartialEq)]
+pub enum Selection {
+    /// Every critical chunk, IHDR, PLTE, IDAT and IEND
+    Critical,
+    /// Every chunk of the listed types
+    Types(Vec<ChunkType>),
+}
+
+impl Selection {
+    fn covers(&self, chunk: &Chunk) -> bool {
+        match self {
+            Selection::Critical => chunk.r#type.is_critical(),
+            Selection::Types(types) => types.contains(&chunk.r#type),
+        }
+    }
+}
+
+impl FromStr for Selection {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Error> {
+        if s == "critical" {
+            return Ok(Selection::Critical);
+        }
+
+        let types = s
+            .split(',')
+            .map(ChunkType::from_str)
+            .collect::<Result<Vec<_>, Error>>()?;
+        ensure!(
+            types
+                .iter()
+                .all(|chunk_type| chunk_type.to_string() != CHUNK_TYPE),
+            "{} chunks cannot be covered",
+            CHUNK_TYPE
+        );
+
+        Ok(Selection::Types(types))
+    }
+}
+
+impl Display for Selection {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Selection::Critical => write!(f, "critical"),
+            Selection::Types(types) => {
+                let types: Vec<String> = types.iter().map(ToString::to_string).collect();
+                write!(f, "{}", types.join(","))
+            }
+        }
+    }
+}
+
+fn hmac(key: &[u8]) -> Hmac<Sha256> {
+    Hmac::<Sha256>::new_from_slice(key).expect("HMAC takes keys of any size")
+}
+
+/// Covered chunks with their occurrence among chunks of the same type, counting from 1
+fn covered<'a>(png: &'a Png, selection: &Selection) -> Vec<(String, u16, &'a Chunk)> {
+    let mut occurrences: HashMap<String, u16> = HashMap::new();
+
+    png.chunks
+        .iter()
+        .filter(|chunk| selection.covers(chunk))
+        .map(|chunk| {
+            let chunk_type = chunk.r#type.to_string();
+            let occurrence = occurrences.entry(chunk_type.clone()).or_default();
+            *occurrence += 1;
+            (chunk_type, *occurrence, chunk)
+        })
+        .collect()
+}
+
+fn chunk_tag(key: &[u8], chunk: &Chunk) -> [u8; TAG_SIZE] {
+    let mut mac = hmac(key);
+    mac.update(&chunk.r#type.bytes());
+    mac.update(&chunk.data);
+
+    mac.finalize().into_bytes().into()
+}
+
+/// Tags every chunk the selection covers with HMAC-SHA256 into a tag chunk
+///
+/// The tag chunk holds the selection, one tag per covered chunk, so that a failed
+/// verification names the changed chunks, and a tag over all of it.
+pub fn tag(png: &Png, key: &[u8], selection: &Selection) -> Result<Chunk, Error> {
+    ensure!(!key.is_empty(), "Key is empty");
+    let rule = selection.to_string();
+    let covered = covered(png, selection);
+    ensure!(!covered.is_empty(), "No chunks match {}", rule);
+    ensure!(
+        rule.len() <= u8::MAX as usize,
+        "Too many chunk types to cover"
+    );
+    ensure!(
+        covered.len() <= u16::MAX as usize,
+        "Too many chunks to cover"
+    );
+
+    let mut data = vec![rule.len() as u8];
+    data.extend_from_slice(rule.as_bytes());
+    data.extend_from_slice(&(covered.len() as u16).to_be_bytes());
+    for (_, occurrence, chunk) in covered {
+        data.extend_from_slice(&chunk.r#type.bytes());
+        data.extend_from_slice(&occurrence.to_be_bytes());
+        data.extend_from_slice(&chunk_tag(key, chunk));
+    }
+
+    let mut mac = hmac(key);
+    mac.update(LIST_DOMAIN);
+    mac.update(&data);
+    data.extend_from_slice(&mac.finalize().into_bytes());
+
+    Ok(Chunk::new(ChunkType::from_str(CHUNK_TYPE).unwrap(), data))
+}
+
+/// Checks the tag chunk and returns the number of covered chunks
+///
+/// Fails with a list of covered chunks that changed, were removed or were added since tagging.
+pub fn verify(png: &Png, key: &[u8]) -> Result<usize, Error> {
+    let data = &png
+        .chunk_by_type(CHUNK_TYPE)
+        .context("Image has no HMAC tag")?
+        .data;
+    ensure!(data.len() > TAG_SIZE, "HMAC tag chunk is too short");
+
+    let (list, tag) = data.split_at(data.len() - TAG_SIZE);
+    let mut mac = hmac(key);
+    mac.update(LIST_DOMAIN);
+    mac.update(list);
+    mac.verify_slice(tag)
+        .map_err(|_| anyhow!("Wrong key, or the HMAC tag chunk was tampered with"))?;
+
+    let rule_len = list[0] as usize;
+    ensure!(list.len() >= 3 + rule_len, "HMAC tag chunk is corrupted");
+    let selection = Selection::from_str(std::str::from_utf8(&list[1..1 + rule_len])?)?;
+    let entries = &list[3 + rule_len..];
+    ensure!(
+        entries.len().is_multiple_of(ENTRY_SIZE),
+        "HMAC tag chunk is corrupted"
+    );
+
+    let mut changes = Vec::new();
+    let mut current: Vec<(String, u16, &Chunk)> = covered(png, &selection);
+    for entry in entries.chunks(ENTRY_SIZE) {
+        let chunk_type = ChunkType::try_from(<[u8; 4]>::try_from(&entry[..4])?)?.to_string();
+        let occurrence = u16::from_be_bytes([entry[4], entry[5]]);
+
+        match current
+            .iter()
+            .position(|(other, n, _)| *other == chunk_type && *n == occurrence)
+        {
+            Some(i) => {
+                let (_, _, chunk) = current.remove(i);
+                if chunk_tag(key, chunk) != entry[6..] {
+                    changes.push(format!("{} {} changed", chunk_type, occurrence));
+                }
+            }
+            None => changes.push(format!("{} {} removed", chunk_type, occurrence)),
+        }
+    }
+    changes.extend(
+        current
+            .iter()
+            .map(|(chunk_type, occurrence, _)| format!("{} {} added", chunk_type, occurrence)),
+    );
+
+    if !changes.is_empty() {
+        bail!("Covered chunks changed: {}", changes.join(", "));
+    }
+
+    Ok(entries.len() / ENTRY_SIZE)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::png::tests::PNG_FILE;
+
+    use super::*;
+
+    fn tagged_png(selection: &str) -> Png {
+        let mut png = Png::try_from(&PNG_FILE[..]).unwrap();
+        let selection = Selection::from_str(selection).unwrap();
+        png.append_chunk(tag(&png, b"pre-shared key", &selection).unwrap());
+        png
+    }
+
+    #[test]
+    fn test_selection() {
+        assert_eq!(
+            Selection::from_str("critical").unwrap(),
+            Selection::Critical
+        );
+        let types = Selection::from_str("IHDR,ruSt").unwrap();
+        assert_eq!(types.to_string(), "IHDR,ruSt");
+        assert!(Selection::from_str("hmAC").is_err());
+        assert!(Selection::from_str("IHDR,").is_err());
+    }
+
+    #[test]
+    fn test_tag_verify() {
+        let mut png = tagged_png("critical");
+        // IHDR, IDAT, RuSt and IEND
+        assert_eq!(verify(&png, b"pre-shared key").unwrap(), 4);
+
+        png.append_chunk(Chunk::new(
+            ChunkType::from_str("tEXt").unwrap(),
+            b"Comment\0Not covered".to_vec(),
+        ));
+        assert_eq!(verify(&png, b"pre-shared key").unwrap(), 4);
+        assert!(verify(&png, b"wrong key").is_err());
+    }
+
+    #[test]
+    fn test_changed_chunks() {
+        let mut png = tagged_png("critical");
+        png.remove_chunk("RuSt").unwrap();
+        png.chunks[0].data[0] ^= 1;
+        png.append_chunk(Chunk::new(
+            ChunkType::from_str("RuSt").unwrap(),
+            b"one".to_vec(),
+        ));
+        png.append_chunk(Chunk::new(
+            ChunkType::from_str("RuSt").unwrap(),
+            b"two".to_vec(),
+        ));
+
+        assert_eq!(
+            verify(&png, b"pre-shared key").unwrap_err().to_string(),
+            "Covered chunks changed: IHDR 1 changed, RuSt 1 changed, RuSt 2 added"
+        );
+    }
+}
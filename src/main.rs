@@ -1,16 +1,50 @@
 use std::fs;
-use std::path::PathBuf;
+use std::io;
+use std::io::{IsTerminal, Write};
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
 
-use clap::{Parser, Subcommand};
+use anyhow::{anyhow, bail, ensure, Context, Result};
+use clap::{Parser, Subcommand, ValueEnum};
 
+use crate::camouflage::Style;
+use crate::capacity::Report;
 use crate::chunk::Chunk;
 use crate::chunk_type::ChunkType;
+use crate::compress::Algorithm;
+use crate::envelope::{ContentType, Encryption, Envelope, Metadata};
+use crate::image::{EncodeOptions, FilterStrategy};
+use crate::keyring::Keyring;
+use crate::lsb::{AlphaMode, Channels, LsbOptions};
+use crate::mac::Selection;
 use crate::png::Png;
+use crate::recipient::{Identity, Recipient};
+use crate::scan::ScanReport;
+use crate::share::Share;
+use crate::signature::{Signer, Verifier};
 
+mod alpha_zero;
+mod archive;
+mod camouflage;
+mod capacity;
 mod chunk;
 mod chunk_type;
+mod compress;
+mod crypto;
+mod decoy;
+mod ecc;
+mod envelope;
+mod fragment;
+mod image;
+mod keyring;
+mod lsb;
+mod mac;
+mod palette;
 mod png;
+mod recipient;
+mod scan;
+mod share;
+mod signature;
 mod util;
 
 /// Hide secret messages in PNG files.
@@ -28,15 +62,98 @@ enum Commands {
     Encode {
         // Path of target PNG file to encode the secret message
         file: PathBuf,
-        chunk_type: String,
-        message: String,
+        /// Chunk type to write, or comma-separated types to rotate through with --fragments,
+        /// omitted for methods other than chunk
+        chunk_type: Option<String>,
+        message: Option<String>,
+        /// File to hide instead of a message, with its filename and MIME type
+        #[arg(long, conflicts_with_all = ["message", "decoy"])]
+        input: Option<PathBuf>,
+        /// Directory to hide instead of a message, packed into a tar archive
+        #[arg(long, conflicts_with_all = ["message", "decoy", "input"])]
+        input_dir: Option<PathBuf>,
+        /// Split the message into this many chunks that can be reassembled (chunk method)
+        #[arg(long, default_value_t = 1)]
+        fragments: usize,
+        /// Compress the message before it is encrypted and hidden
+        #[arg(long, value_enum)]
+        compress: Option<Algorithm>,
+        /// Add this many Reed-Solomon parity bytes per 255 byte block, correcting half as many
+        /// wrong bytes; the length headers of the lsb, alpha-zero and palette-order methods are
+        /// not covered
+        #[arg(long, value_parser = clap::value_parser!(u8).range(2..=128))]
+        ecc: Option<u8>,
+        /// Harmless message to reveal under pressure, can be repeated; every message is
+        /// encrypted with its own password into a container that hides how many there are
+        #[arg(long)]
+        decoy: Vec<String>,
+        /// Prompt for a password and encrypt the message with it
+        #[arg(long, conflicts_with = "decoy")]
+        encrypt: bool,
+        /// Public key to encrypt the message to, can be repeated for several recipients
+        #[arg(long, conflicts_with_all = ["decoy", "encrypt"])]
+        recipient: Vec<Recipient>,
+        /// Name of a keyring key to encrypt the message to, can be repeated
+        #[arg(long, conflicts_with_all = ["decoy", "encrypt"])]
+        to: Vec<String>,
+        /// Signing key file to sign the message and chunk type with (chunk method)
+        #[arg(long)]
+        sign: Option<PathBuf>,
+        /// Name of a keyring signing key to sign the message with (chunk method)
+        #[arg(long, conflicts_with = "sign")]
+        sign_as: Option<String>,
+        /// Where the secret message is hidden
+        #[arg(long, value_enum, default_value_t = Method::Chunk)]
+        method: Method,
+        /// Number of low bits of every sample to use (lsb method)
+        #[arg(long, default_value_t = 1, value_parser = clap::value_parser!(u8).range(1..=4))]
+        bits: u8,
+        /// Channels to use, any of r, g, b, a and y (gray) or all (lsb method)
+        #[arg(long, default_value = "all")]
+        channels: Channels,
+        /// Select pixels by their transparency (lsb method)
+        #[arg(long, value_enum, default_value_t = AlphaMode::Any)]
+        alpha: AlphaMode,
+        /// Prompt for a password that scatters the message over the image (lsb method)
+        #[arg(long)]
+        password: bool,
+        /// Metadata the message is disguised as (camouflage method)
+        #[arg(long, value_enum, default_value_t = Style::Xmp)]
+        style: Style,
+        /// Replace data that already follows IEND, like an appended archive (trailer method)
+        #[arg(long)]
+        overwrite_trailer: bool,
     },
 
     /// Decode the secret message in the chunk.
     Decode {
         // Path of target PNG file to decode the secret message
         file: PathBuf,
-        chunk_type: String,
+        /// Chunk type to read, or comma-separated types with --fragmented, omitted for methods
+        /// other than chunk
+        chunk_type: Option<String>,
+        /// Reassemble a message that was split with --fragments (chunk method)
+        #[arg(long)]
+        fragmented: bool,
+        /// Identity file to decrypt a message that was encrypted to its public key
+        #[arg(long)]
+        identity: Option<PathBuf>,
+        /// Name of a keyring key to decrypt a message that was encrypted to it
+        #[arg(long, conflicts_with = "identity")]
+        decrypt_as: Option<String>,
+        /// Where the secret message is hidden
+        #[arg(long, value_enum, default_value_t = Method::Chunk)]
+        method: Method,
+        /// Prompt for the password the message was hidden with (lsb method)
+        #[arg(long)]
+        password: bool,
+        /// File to write the message to, or a directory to write a hidden file under its name
+        /// or to extract a hidden directory into
+        #[arg(long)]
+        output: Option<PathBuf>,
+        /// List the entries of a hidden directory without extracting them
+        #[arg(long, conflicts_with = "output")]
+        list: bool,
     },
 
     /// Remove a chunk by its type.
@@ -46,6 +163,134 @@ enum Commands {
         chunk_type: String,
     },
 
+    /// Re-encode the image data with the given filter and compression settings.
+    Reencode {
+        /// Path of target PNG file to re-encode
+        file: PathBuf,
+        /// Filter of every scanline: none, sub, up, average, paeth or adaptive
+        #[arg(long, default_value = "adaptive")]
+        filter: FilterStrategy,
+        /// Deflate compression level
+        #[arg(long, default_value_t = 6, value_parser = clap::value_parser!(u32).range(0..=9))]
+        level: u32,
+        /// Interlace the image with Adam7
+        #[arg(long)]
+        interlace: bool,
+        /// Maximum data size of each IDAT chunk in bytes
+        #[arg(long, default_value_t = 8192)]
+        idat_size: usize,
+    },
+
+    /// Merge or split IDAT chunks without re-compressing the image data.
+    Idat {
+        #[command(subcommand)]
+        action: IdatAction,
+    },
+
+    /// Show how many bytes every method can hide in the image.
+    Capacity {
+        /// Path of target PNG file to estimate its capacity
+        file: PathBuf,
+        /// Number of low bits of every sample to use (lsb method)
+        #[arg(long, default_value_t = 1, value_parser = clap::value_parser!(u8).range(1..=4))]
+        bits: u8,
+        /// Channels to use, any of r, g, b, a and y (gray) or all (lsb method)
+        #[arg(long, default_value = "all")]
+        channels: Channels,
+        /// Select pixels by their transparency (lsb method)
+        #[arg(long, value_enum, default_value_t = AlphaMode::Any)]
+        alpha: AlphaMode,
+    },
+
+    /// Look for signs of hidden data in the PNG file.
+    Scan {
+        /// Path of target PNG file to scan
+        file: PathBuf,
+        /// Output format of the report
+        #[arg(long, value_enum, default_value_t = Format::Text)]
+        format: Format,
+    },
+
+    /// Split a secret across PNG files so that a threshold of them recovers it.
+    Share {
+        #[command(subcommand)]
+        action: ShareAction,
+    },
+
+    /// Check the signature of a message that was hidden with --sign.
+    Verify {
+        /// Path of target PNG file to verify
+        file: PathBuf,
+        /// Type of the signed chunk
+        chunk_type: String,
+        /// Public key the message must be signed by
+        #[arg(long)]
+        pubkey: Option<Verifier>,
+        /// Name of a keyring key the message must be signed by
+        #[arg(long, conflicts_with = "pubkey")]
+        from: Option<String>,
+    },
+
+    /// Sign the header and pixels of the image to detect later changes.
+    SignImage {
+        /// Path of target PNG file to sign
+        file: PathBuf,
+        /// Signing key file to sign with
+        #[arg(long, required_unless_present = "sign_as")]
+        key: Option<PathBuf>,
+        /// Name of a keyring signing key to sign with
+        #[arg(long, conflicts_with = "key")]
+        sign_as: Option<String>,
+    },
+
+    /// Check that the image did not change since it was signed with sign-image.
+    VerifyImage {
+        /// Path of target PNG file to verify
+        file: PathBuf,
+        /// Public key the image must be signed by
+        #[arg(long)]
+        pubkey: Option<Verifier>,
+        /// Name of a keyring key the image must be signed by
+        #[arg(long, conflicts_with = "pubkey")]
+        from: Option<String>,
+    },
+
+    /// Tag chunks with HMAC-SHA256 and a pre-shared key to detect later changes.
+    Mac {
+        /// Path of target PNG file to tag
+        file: PathBuf,
+        /// Path of the file holding the pre-shared key
+        #[arg(long)]
+        key: PathBuf,
+        /// Chunks to cover, critical or comma-separated chunk types
+        #[arg(long, default_value = "critical")]
+        chunks: Selection,
+    },
+
+    /// Check the HMAC tag of the chunks and name the ones that changed.
+    VerifyMac {
+        /// Path of target PNG file to verify
+        file: PathBuf,
+        /// Path of the file holding the pre-shared key
+        #[arg(long)]
+        key: PathBuf,
+    },
+
+    /// Manage named keys in the local keyring.
+    Keys {
+        #[command(subcommand)]
+        action: KeysAction,
+    },
+
+    /// Generate a key pair to encrypt messages to with --recipient or to --sign them.
+    Keygen {
+        /// Path of the key file to create, printed if omitted
+        output: Option<PathBuf>,
+        /// Generate a signing key pair for --sign instead
+        #[arg(long)]
+        signing: bool,
+    },
+
     /// Print all chunks inside the PNG file.
     Print {
         /// Path of target PNG file to print its contents
@@ -53,6 +298,252 @@ enum Commands {
     },
 }
 
+#[derive(Subcommand)]
+enum IdatAction {
+    /// Merge all IDAT chunks into one.
+    Merge {
+        /// Path of target PNG file to merge its IDAT chunks
+        file: PathBuf,
+    },
+
+    /// Split the image data into IDAT chunks of a maximum size.
+    Split {
+        /// Path of target PNG file to split its IDAT chunks
+        file: PathBuf,
+        /// Maximum data size of each IDAT chunk in bytes
+        #[arg(long, default_value_t = 65536)]
+        size: usize,
+    },
+}
+
+#[derive(Subcommand)]
+enum KeysAction {
+    /// Add a public key, or a key file whose secret key is encrypted under a passphrase.
+    Add {
+        /// Name to refer to the key by
+        name: String,
+        /// Public key, or path of a key file made by keygen
+        key: String,
+    },
+
+    /// List all keys of the keyring.
+    List,
+
+    /// Remove a key from the keyring.
+    Remove {
+        /// Name of the key to remove
+        name: String,
+    },
+
+    /// Print the public key, or the whole key file with --secret.
+    Export {
+        /// Name of the key to export
+        name: String,
+        /// Decrypt and print the secret key
+        #[arg(long)]
+        secret: bool,
+    },
+}
+
+#[derive(Subcommand)]
+enum ShareAction {
+    /// Split the secret file into one share per PNG file.
+    Split {
+        /// Number of shares needed to recover the secret
+        #[arg(long)]
+        threshold: u8,
+        /// Number of shares, one for every PNG file
+        #[arg(long)]
+        shares: u8,
+        /// Path of the file to split
+        secret: PathBuf,
+        /// Paths of target PNG files to add a share to
+        #[arg(required = true)]
+        files: Vec<PathBuf>,
+    },
+
+    /// Recover the secret from PNG files that hold enough shares.
+    Combine {
+        /// Paths of PNG files with shares of the same secret
+        #[arg(required = true)]
+        files: Vec<PathBuf>,
+        /// Write the secret to this file instead of standard output
+        #[arg(long)]
+        output: Option<PathBuf>,
+    },
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum Method {
+    /// A chunk of the given type
+    Chunk,
+    /// Least significant bits of the pixel samples
+    Lsb,
+    /// Order of the palette entries of an indexed image
+    PaletteOrder,
+    /// Bytes appended after the IEND chunk
+    Trailer,
+    /// Color samples of fully transparent pixels
+    AlphaZero,
+    /// A standard-looking XMP packet or comment
+    Camouflage,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum Format {
+    Text,
+    Json,
+}
+
+/// Parses a comma-separated list of chunk types
+fn chunk_types(chunk_types: &str) -> Result<Vec<ChunkType>> {
+    chunk_types.split(',').map(ChunkType::from_str).collect()
+}
+
+/// Methods other than chunk take no chunk type, so their only positional argument is the message
+fn encode_arguments(
+    method: Method,
+    chunk_type: &Option<String>,
+    message: &Option<String>,
+    input: bool,
+    fragments: usize,
+) -> Result<(Vec<ChunkType>, Option<String>)> {
+    let (chunk_types, message) = match (method, chunk_type, message) {
+        (Method::Chunk, Some(chunk_type), message) => (chunk_types(chunk_type)?, message.clone()),
+        (Method::Chunk, None, _) => bail!("Chunk method needs a chunk type"),
+        (_, message, None) => (Vec::new(), message.clone()),
+        (_, _, Some(_)) => bail!("Only the chunk method takes a chunk type"),
+    };
+    ensure!(
+        chunk_types.len() <= 1 || fragments > 1,
+        "Several chunk types need --fragments to spread the message over them"
+    );
+    ensure!(
+        message.is_some() != input,
+        "Give either a message, --input or --input-dir"
+    );
+
+    Ok((chunk_types, message))
+}
+
+/// Reads a file to hide with its filename and the MIME type its extension suggests
+fn read_input(path: &Path) -> Result<(Vec<u8>, String, Option<String>)> {
+    let filename = path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .context("Input has no UTF-8 filename")?;
+    ensure!(
+        filename.len() <= u8::MAX as usize,
+        "Filenames over {} bytes cannot be stored",
+        u8::MAX
+    );
+    let content = fs::read(path).with_context(|| format!("Cannot read {}", path.display()))?;
+    let mime_type = mime_guess::from_path(path).first_raw().map(String::from);
+
+    Ok((content, filename.to_string(), mime_type))
+}
+
+/// Message of a chunk hidden before envelopes existed, which is plain text
+fn raw_text(data: Vec<u8>) -> Result<Vec<u8>> {
+    if std::str::from_utf8(&data).is_err() {
+        ensure!(
+            !fragment::has_header(&data),
+            "Chunk holds a fragment, decode it with --fragmented"
+        );
+        bail!("Chunk holds no pngme message");
+    }
+
+    Ok(data)
+}
+
+/// Entries of a hidden directory, for decode --list
+fn list_archive(content_type: ContentType, data: &[u8]) -> Result<Vec<(PathBuf, u64)>> {
+    ensure!(
+        content_type == ContentType::Archive,
+        "Secret message is not a directory hidden with --input-dir"
+    );
+
+    archive::entries(data)
+}
+
+/// Writes a hidden file to stdout, unless it is a terminal that would show garbage
+fn write_binary(data: &[u8]) -> Result<()> {
+    ensure!(
+        !io::stdout().is_terminal(),
+        "Secret message is a binary file, give --output or redirect the output"
+    );
+    io::stdout().write_all(data)?;
+
+    Ok(())
+}
+
+/// Decrypts the data, asking for a password or reading an identity as the encryption needs
+fn decrypt_body(
+    data: Vec<u8>,
+    encryption: Encryption,
+    identity: &Option<PathBuf>,
+    decrypt_as: &Option<String>,
+) -> Result<Vec<u8>> {
+    Ok(match encryption {
+        Encryption::None => data,
+        Encryption::Password => {
+            crypto::decrypt(&data, &prompt_password("Password of the message", false)?)?
+        }
+        Encryption::Decoy => decoy::open(&data, &prompt_password("Password", false)?)?,
+        Encryption::Recipients => {
+            let identities = match (identity, decrypt_as) {
+                (Some(path), _) => Identity::read_file(path)?,
+                (None, Some(name)) => vec![keyring()?.identity(
+                    name,
+                    &prompt_password(&format!("Passphrase of {}", name), false)?,
+                )?],
+                (None, None) => {
+                    bail!("Message is encrypted to public keys, give --identity or --decrypt-as")
+                }
+            };
+            recipient::decrypt(&data, &identities)?
+        }
+    })
+}
+
+fn keyring() -> Result<Keyring> {
+    Keyring::load(&Keyring::default_path()?)
+}
+
+/// Signing key from a key file or a keyring key, asking for the passphrase of the latter
+fn signer(key: Option<&PathBuf>, name: Option<&String>) -> Result<Signer> {
+    match (key, name) {
+        (Some(path), _) => Signer::read_file(path),
+        (None, Some(name)) => keyring()?.signer(
+            name,
+            &prompt_password(&format!("Passphrase of {}", name), false)?,
+        ),
+        (None, None) => bail!("No signing key given"),
+    }
+}
+
+/// Public key given directly or as the name of a keyring key, if any
+fn verifier(pubkey: Option<&Verifier>, name: Option<&String>) -> Result<Option<Verifier>> {
+    match (pubkey, name) {
+        (Some(pubkey), _) => Ok(Some(pubkey.clone())),
+        (None, Some(name)) => Ok(Some(keyring()?.verifier(name)?)),
+        (None, None) => Ok(None),
+    }
+}
+
+/// Reads a password from the terminal without echoing it, asking twice if it is a new one
+fn prompt_password(prompt: &str, confirm: bool) -> Result<String> {
+    let password = rpassword::prompt_password(format!("{}: ", prompt))?;
+    if confirm {
+        ensure!(
+            password == rpassword::prompt_password(format!("Confirm {}: ", prompt.to_lowercase()))?,
+            "Passwords do not match"
+        );
+    }
+
+    Ok(password)
+}
+
 fn main() {
     let cli = Cli::parse();
 
@@ -61,27 +552,319 @@ fn main() {
             file,
             chunk_type,
             message,
+            input,
+            input_dir,
+            fragments,
+            compress,
+            ecc,
+            decoy,
+            encrypt,
+            recipient,
+            to,
+            sign,
+            sign_as,
+            method,
+            bits,
+            channels,
+            alpha,
+            password,
+            style,
+            overwrite_trailer,
         }) => {
+            let (chunk_types, message) = encode_arguments(
+                *method,
+                chunk_type,
+                message,
+                input.is_some() || input_dir.is_some(),
+                *fragments,
+            )
+            .expect("Invalid arguments");
+            // Files and directories carry their metadata in front, to be encrypted with them
+            let (content, content_type) = match (input, input_dir) {
+                (Some(path), _) => {
+                    let (content, filename, mime_type) =
+                        read_input(path).expect("Cannot read input file");
+                    let metadata = Metadata {
+                        filename: Some(filename),
+                        mime_type,
+                    };
+                    (
+                        metadata.prepend(&content).expect("Cannot read input file"),
+                        ContentType::Binary,
+                    )
+                }
+                (None, Some(dir)) => {
+                    let metadata = Metadata {
+                        filename: dir
+                            .file_name()
+                            .and_then(|name| name.to_str())
+                            .filter(|name| name.len() <= u8::MAX as usize)
+                            .map(String::from),
+                        mime_type: Some(String::from("application/x-tar")),
+                    };
+                    (
+                        archive::pack(dir)
+                            .and_then(|content| metadata.prepend(&content))
+                            .expect("Cannot pack directory"),
+                        ContentType::Archive,
+                    )
+                }
+                (None, None) => (message.unwrap_or_default().into_bytes(), ContentType::Text),
+            };
+            let compressed = |message: &[u8]| match compress {
+                Some(algorithm) => {
+                    compress::compress(message, *algorithm).expect("Cannot compress message")
+                }
+                None => message.to_vec(),
+            };
+            let payload = if *encrypt {
+                let password =
+                    prompt_password("Password of the message", true).expect("Cannot read password");
+                crypto::encrypt(&compressed(&content), &password).expect("Cannot encrypt message")
+            } else if !recipient.is_empty() || !to.is_empty() {
+                let mut recipients = recipient.clone();
+                if !to.is_empty() {
+                    let keyring = keyring().expect("Cannot read keyring");
+                    recipients.extend(
+                        to.iter()
+                            .map(|name| keyring.recipient(name).expect("Cannot find recipient")),
+                    );
+                }
+                recipient::encrypt(&compressed(&content), &recipients)
+                    .expect("Cannot encrypt message")
+            } else if decoy.is_empty() {
+                compressed(&content)
+            } else {
+                let passwords: Vec<String> =
+                    std::iter::once(String::from("Password of the message"))
+                        .chain((1..=decoy.len()).map(|i| format!("Password of decoy {}", i)))
+                        .map(|prompt| prompt_password(&prompt, true).expect("Cannot read password"))
+                        .collect();
+                let messages: Vec<Vec<u8>> = std::iter::once(content.as_slice())
+                    .chain(decoy.iter().map(String::as_bytes))
+                    .map(compressed)
+                    .collect();
+                let payloads: Vec<(&[u8], &str)> = messages
+                    .iter()
+                    .map(Vec::as_slice)
+                    .zip(passwords.iter().map(String::as_str))
+                    .collect();
+                decoy::seal(&payloads).expect("Cannot encrypt messages")
+            };
+            let encryption = if *encrypt {
+                Encryption::Password
+            } else if !recipient.is_empty() || !to.is_empty() {
+                Encryption::Recipients
+            } else if !decoy.is_empty() {
+                Encryption::Decoy
+            } else {
+                Encryption::None
+            };
+            let payload = Envelope {
+                compressed: compress.is_some(),
+                ecc: ecc.is_some(),
+                signed: sign.is_some() || sign_as.is_some(),
+                encryption,
+                content_type,
+                ..Envelope::new(payload)
+            }
+            .as_bytes();
+            let payload = match ecc {
+                Some(parity) => {
+                    ecc::encode(&payload, *parity).expect("Cannot add error correction")
+                }
+                None => payload,
+            };
+            let signer = (sign.is_some() || sign_as.is_some())
+                .then(|| {
+                    ensure!(
+                        *method == Method::Chunk && *fragments == 1,
+                        "Only a message in a single chunk can be signed"
+                    );
+                    signer(sign.as_ref(), sign_as.as_ref())
+                })
+                .transpose()
+                .expect("Cannot sign message");
             let mut png = Png::from(file);
-            png.append_chunk(Chunk::new(
-                ChunkType::from_str(chunk_type).expect("Cannot create chunk"),
-                message.clone().into_bytes(),
-            ));
+
+            match method {
+                Method::Chunk if *fragments == 1 => {
+                    let chunk = Chunk::new(chunk_types[0].clone(), payload);
+                    if let Some(signer) = &signer {
+                        png.append_chunk(signature::sign(&chunk, signer));
+                    }
+                    png.append_chunk(chunk);
+                }
+                Method::Chunk => fragment::split(&payload, &chunk_types, *fragments)
+                    .expect("Cannot split message")
+                    .into_iter()
+                    .for_each(|chunk| png.append_chunk(chunk)),
+                Method::Lsb | Method::AlphaZero => {
+                    let mut image = png.raw_image().expect("Cannot decode image data");
+                    if *method == Method::Lsb {
+                        let options = LsbOptions {
+                            bits: *bits,
+                            channels: *channels,
+                            alpha: *alpha,
+                        };
+                        let key = password.then(|| {
+                            lsb::derive_key(
+                                &prompt_password("Password", true).expect("Cannot read password"),
+                            )
+                            .expect("Cannot derive key")
+                        });
+                        lsb::embed(&mut image, &payload, &options, key.as_ref())
+                    } else {
+                        alpha_zero::embed(&mut image, &payload)
+                    }
+                    .expect("Cannot hide message");
+                    let options = EncodeOptions {
+                        interlaced: png.ihdr().expect("Invalid IHDR").interlaced,
+                        ..EncodeOptions::default()
+                    };
+                    png.replace_image(&image, &options)
+                        .expect("Cannot encode image data");
+                }
+                Method::PaletteOrder => {
+                    palette::embed(&mut png, &payload).expect("Cannot hide message");
+                }
+                Method::Trailer => png
+                    .set_trailer(payload, *overwrite_trailer)
+                    .expect("Cannot replace trailing data without --overwrite-trailer"),
+                Method::Camouflage => camouflage::embed(&mut png, &payload, *style),
+            }
             fs::write(file, png.as_bytes()).expect("Cannot write PNG file");
 
             println!("Successfully added a secret message to file");
         }
 
-        Some(Commands::Decode { file, chunk_type }) => {
+        Some(Commands::Decode {
+            file,
+            chunk_type,
+            fragmented,
+            identity,
+            decrypt_as,
+            method,
+            password,
+            output,
+            list,
+        }) => {
             let png = Png::from(file);
-            let chunk = png.chunk_by_type(chunk_type).expect("Chunk does not exist");
+            let key = password.then(|| {
+                lsb::derive_key(&prompt_password("Password", false).expect("Cannot read password"))
+                    .expect("Cannot derive key")
+            });
+            let data = match (method, chunk_type) {
+                (Method::Chunk, Some(chunk_type)) if *fragmented => chunk_types(chunk_type)
+                    .and_then(|chunk_types| fragment::reassemble(&png, &chunk_types)),
+                (Method::Chunk, Some(chunk_type)) => png
+                    .chunk_by_type(chunk_type)
+                    .context("Chunk does not exist")
+                    .map(|chunk| chunk.data.clone()),
+                (Method::Chunk, None) => Err(anyhow!("Chunk type is missing")),
+                (Method::Lsb, _) => png
+                    .raw_image()
+                    .and_then(|image| lsb::extract(&image, key.as_ref())),
+                (Method::PaletteOrder, _) => palette::extract(&png),
+                (Method::AlphaZero, _) => png
+                    .raw_image()
+                    .and_then(|image| alpha_zero::extract(&image)),
+                (Method::Trailer, _) => Ok(png.trailer.clone()),
+                (Method::Camouflage, _) => camouflage::extract(&png),
+            }
+            .expect("Cannot decode the secret message");
 
-            println!(
-                "{}",
-                chunk
-                    .data_as_string()
-                    .expect("Chunk data is not in UTF-8 format")
-            );
+            let (data, envelope) = match Envelope::find(&data) {
+                Some(found) => {
+                    let (mut envelope, corrected) = found.expect("Cannot read envelope");
+                    if envelope.ecc {
+                        eprintln!("Corrected {} wrong bytes", corrected);
+                    }
+                    if envelope.signed {
+                        eprintln!("Message is signed, check the signature with pngme verify");
+                    }
+                    let body = std::mem::take(&mut envelope.body);
+                    let data = decrypt_body(body, envelope.encryption, identity, decrypt_as)
+                        .expect("Cannot decrypt the secret message");
+                    let data = if envelope.compressed {
+                        compress::decompress(&data).expect("Cannot decompress the secret message")
+                    } else {
+                        data
+                    };
+                    (data, Some(envelope))
+                }
+                // Chunks hidden before envelopes existed hold the raw text, other methods always
+                // write an envelope
+                None => {
+                    assert!(
+                        *method == Method::Chunk,
+                        "Cannot decode the secret message: No pngme message in {}",
+                        method.to_possible_value().unwrap().get_name()
+                    );
+                    (
+                        raw_text(data).expect("Cannot decode the secret message"),
+                        None,
+                    )
+                }
+            };
+
+            let content_type = envelope
+                .as_ref()
+                .map_or(ContentType::Text, |envelope| envelope.content_type);
+            let (metadata, data) = match content_type {
+                ContentType::Text => (Metadata::default(), data),
+                _ => Metadata::split(&data)
+                    .map(|(metadata, content)| (metadata, content.to_vec()))
+                    .expect("Cannot read the hidden file name"),
+            };
+            let filename = metadata.filename.as_ref();
+            if let Some(filename) = filename {
+                let mime_type = metadata.mime_type.as_deref().unwrap_or("unknown type");
+                eprintln!("Hidden file {} ({})", filename, mime_type);
+            }
+            match (content_type, output) {
+                (content_type, _) if *list => {
+                    for (path, size) in
+                        list_archive(content_type, &data).expect("Cannot list the hidden directory")
+                    {
+                        println!("{:>10}  {}", size, path.display());
+                    }
+                }
+                (ContentType::Archive, output) => {
+                    let count = output
+                        .as_ref()
+                        .context("Secret message is a directory, give --output or --list")
+                        .and_then(|dir| archive::unpack(&data, dir))
+                        .expect("Cannot extract the hidden directory");
+
+                    println!("Extracted {} entries", count);
+                }
+                (_, Some(output)) => {
+                    let path = if output.is_dir() {
+                        // Only the last component, so a crafted name cannot climb out
+                        let name = filename
+                            .and_then(|filename| Path::new(filename).file_name())
+                            .context("Message has no filename, give a file to --output")
+                            .expect("Cannot write output file");
+                        output.join(name)
+                    } else {
+                        output.clone()
+                    };
+                    fs::write(&path, &data).expect("Cannot write output file");
+
+                    println!("Saved the secret message to {}", path.display());
+                }
+                (ContentType::Binary, None) => {
+                    write_binary(&data).expect("Cannot write the secret message");
+                }
+                (ContentType::Text, None) => {
+                    let message = String::from_utf8(data)
+                        .context("Secret message is not UTF-8")
+                        .expect("Cannot print the secret message");
+
+                    println!("{}", message);
+                }
+            }
         }
 
         Some(Commands::Remove { file, chunk_type }) => {
@@ -92,16 +875,262 @@ fn main() {
             println!("Chunk {} is successfully removed!", removed_chunk.r#type);
         }
 
+        Some(Commands::Reencode {
+            file,
+            filter,
+            level,
+            interlace,
+            idat_size,
+        }) => {
+            let mut png = Png::from(file);
+            let image = png.raw_image().expect("Cannot decode image data");
+            png.replace_image(
+                &image,
+                &EncodeOptions {
+                    filter: *filter,
+                    interlaced: *interlace,
+                    level: *level,
+                    idat_size: *idat_size,
+                },
+            )
+            .expect("Cannot encode image data");
+            fs::write(file, png.as_bytes()).expect("Cannot write PNG file");
+
+            println!("Image data is successfully re-encoded!");
+        }
+
+        Some(Commands::Idat { action }) => {
+            let file = match action {
+                IdatAction::Merge { file } | IdatAction::Split { file, .. } => file,
+            };
+            let mut png = Png::from(file);
+            match action {
+                IdatAction::Merge { .. } => png.coalesce_idat(),
+                IdatAction::Split { size, .. } => png.split_idat(*size),
+            }
+            .expect("Cannot rewrite IDAT chunks");
+            fs::write(file, png.as_bytes()).expect("Cannot write PNG file");
+
+            println!("IDAT chunks are successfully rewritten!");
+        }
+
+        Some(Commands::Capacity {
+            file,
+            bits,
+            channels,
+            alpha,
+        }) => {
+            let options = LsbOptions {
+                bits: *bits,
+                channels: *channels,
+                alpha: *alpha,
+            };
+
+            print!(
+                "{}",
+                Report::new(&Png::from(file), &options).expect("Cannot estimate capacity")
+            );
+        }
+
+        Some(Commands::Scan { file, format }) => {
+            let bytes = fs::read(file).expect("Cannot read PNG file");
+            let report = ScanReport::from_bytes(&bytes).expect("Cannot scan PNG file");
+
+            match format {
+                Format::Text => print!("{}", report),
+                Format::Json => println!(
+                    "{}",
+                    report.to_json().expect("Cannot serialize scan report")
+                ),
+            }
+        }
+
+        Some(Commands::Share {
+            action:
+                ShareAction::Split {
+                    threshold,
+                    shares,
+                    secret,
+                    files,
+                },
+        }) => {
+            assert_eq!(
+                *shares as usize,
+                files.len(),
+                "Number of shares must match the number of PNG files"
+            );
+            let secret = fs::read(secret).expect("Cannot read secret file");
+            let shares = share::split(&secret, *threshold, *shares).expect("Cannot split secret");
+
+            for (file, share) in files.iter().zip(shares) {
+                let mut png = Png::from(file);
+                png.chunks
+                    .retain(|chunk| chunk.r#type.to_string() != share::CHUNK_TYPE);
+                png.append_chunk(share.as_chunk());
+                fs::write(file, png.as_bytes()).expect("Cannot write PNG file");
+            }
+
+            println!("Secret is successfully split into {} files!", files.len());
+        }
+
+        Some(Commands::Share {
+            action: ShareAction::Combine { files, output },
+        }) => {
+            let shares: Vec<Share> = files
+                .iter()
+                .map(|file| Share::try_from(&Png::from(file)).expect("Cannot read share"))
+                .collect();
+            let secret = share::combine(&shares).expect("Cannot recover secret");
+
+            match output {
+                Some(output) => fs::write(output, secret).expect("Cannot write secret file"),
+                None => io::stdout()
+                    .write_all(&secret)
+                    .expect("Cannot write secret"),
+            }
+        }
+
+        Some(Commands::Verify {
+            file,
+            chunk_type,
+            pubkey,
+            from,
+        }) => {
+            let png = Png::from(file);
+            let pubkey = verifier(pubkey.as_ref(), from.as_ref()).expect("Cannot find public key");
+            let signer = signature::verify(&png, chunk_type, pubkey.as_ref())
+                .expect("Signature is not valid");
+
+            println!("Valid signature of {} chunk by {}", chunk_type, signer);
+        }
+
+        Some(Commands::SignImage { file, key, sign_as }) => {
+            let mut png = Png::from(file);
+            let signer = signer(key.as_ref(), sign_as.as_ref()).expect("Cannot read signing key");
+            let chunk = signature::sign_image(&png, &signer).expect("Cannot sign image");
+            png.chunks
+                .retain(|chunk| chunk.r#type.to_string() != signature::IMAGE_CHUNK_TYPE);
+            png.append_chunk(chunk);
+            fs::write(file, png.as_bytes()).expect("Cannot write PNG file");
+
+            println!("Image is signed by {}", signer.verifier());
+        }
+
+        Some(Commands::VerifyImage { file, pubkey, from }) => {
+            let png = Png::from(file);
+            let pubkey = verifier(pubkey.as_ref(), from.as_ref()).expect("Cannot find public key");
+            let signer =
+                signature::verify_image(&png, pubkey.as_ref()).expect("Image is not intact");
+
+            println!("Header and pixels are unchanged since signed by {}", signer);
+        }
+
+        Some(Commands::Mac { file, key, chunks }) => {
+            let mut png = Png::from(file);
+            png.chunks
+                .retain(|chunk| chunk.r#type.to_string() != mac::CHUNK_TYPE);
+            let key = fs::read(key).expect("Cannot read key file");
+            let chunk = mac::tag(&png, &key, chunks).expect("Cannot tag chunks");
+            png.append_chunk(chunk);
+            fs::write(file, png.as_bytes()).expect("Cannot write PNG file");
+
+            println!("Successfully tagged {} chunks", chunks);
+        }
+
+        Some(Commands::VerifyMac { file, key }) => {
+            let png = Png::from(file);
+            let key = fs::read(key).expect("Cannot read key file");
+            let covered = mac::verify(&png, &key).expect("Verification failed");
+
+            println!("All {} covered chunks are unchanged", covered);
+        }
+
+        Some(Commands::Keys { action }) => {
+            let path = Keyring::default_path().expect("Cannot find keyring");
+            let mut keyring = Keyring::load(&path).expect("Cannot read keyring");
+
+            match action {
+                KeysAction::Add { name, key } => {
+                    match fs::read_to_string(key) {
+                        Ok(key_file) => {
+                            let passphrase =
+                                prompt_password(&format!("Passphrase of {}", name), true)
+                                    .expect("Cannot read passphrase");
+                            keyring.add_secret(name, &key_file, &passphrase)
+                        }
+                        Err(_) => keyring.add_public(name, key),
+                    }
+                    .expect("Cannot add key");
+                    keyring.save(&path).expect("Cannot write keyring");
+
+                    println!("Added key {}", name);
+                }
+                KeysAction::List => {
+                    for entry in keyring.entries() {
+                        println!("{}", entry);
+                    }
+                }
+                KeysAction::Remove { name } => {
+                    keyring.remove(name).expect("Cannot remove key");
+                    keyring.save(&path).expect("Cannot write keyring");
+
+                    println!("Removed key {}", name);
+                }
+                KeysAction::Export { name, secret } => {
+                    let entry = keyring.entry(name).expect("Cannot export key");
+                    if *secret {
+                        let passphrase = prompt_password(&format!("Passphrase of {}", name), false)
+                            .expect("Cannot read passphrase");
+                        print!(
+                            "{}",
+                            entry.export_secret(&passphrase).expect("Cannot export key")
+                        );
+                    } else {
+                        println!("{}", entry.public_key);
+                    }
+                }
+            }
+        }
+
+        Some(Commands::Keygen { output, signing }) => {
+            let (key_file, public_key) = if *signing {
+                let signer = Signer::generate();
+                (signer.to_file_string(), signer.verifier().to_string())
+            } else {
+                let identity = Identity::generate();
+                (identity.to_file_string(), identity.recipient().to_string())
+            };
+
+            match output {
+                Some(output) => {
+                    let mut options = fs::OpenOptions::new();
+                    options.write(true).create_new(true);
+                    #[cfg(unix)]
+                    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+                    options
+                        .open(output)
+                        .and_then(|mut file| file.write_all(key_file.as_bytes()))
+                        .expect("Cannot write key file");
+
+                    println!("Public key: {}", public_key);
+                }
+                None => print!("{}", key_file),
+            }
+        }
+
         Some(Commands::Print { file }) => {
+            let png = Png::from(file);
             println!(
                 "{}",
-                Png::from(file)
-                    .chunks
-                    .into_iter()
+                png.chunks
+                    .iter()
                     .map(|chunk| chunk.r#type.to_string())
                     .collect::<Vec<String>>()
                     .join(" ")
             );
+            if !png.trailer.is_empty() {
+                println!("{} bytes after IEND", png.trailer.len());
+            }
         }
 
         None => {
@@ -0,0 +1,359 @@
+use anyhow::{bail, ensure, Context, Error, Result};
+
+use crate::chunk::Chunk;
+use crate::image::{ColorType, EncodeOptions, RawImage};
+use crate::png::Png;
+
+/// Size of the payload length that is stored in the palette order before the payload
+pub const HEADER_SIZE: usize = 2;
+
+/// Palette entries as RGBA, with the alpha values of the tRNS chunk
+fn entries(png: &Png) -> Result<Vec<[u8; 4]>, Error> {
+    ensure!(
+        png.ihdr()?.color_type == ColorType::Indexed,
+        "Only indexed images have a palette to reorder"
+    );
+
+    let palette = &png
+        .chunk_by_type("PLTE")
+        .context("PLTE chunk does not exist")?
+        .data;
+    let alpha = png
+        .chunk_by_type("tRNS")
+        .map_or(&[][..], |chunk| &chunk.data[..]);
+    let entries: Vec<[u8; 4]> = palette
+        .chunks_exact(3)
+        .enumerate()
+        .map(|(i, rgb)| [rgb[0], rgb[1], rgb[2], *alpha.get(i).unwrap_or(&255)])
+        .collect();
+
+    let mut sorted = entries.clone();
+    sorted.sort_unstable();
+    sorted.dedup();
+    ensure!(
+        sorted.len() == entries.len(),
+        "Palette has duplicate entries"
+    );
+
+    Ok(entries)
+}
+
+/// Indices of the entries sorted by color, the order that stands for the number zero
+fn canonical_order(entries: &[[u8; 4]]) -> Vec<usize> {
+    let mut order: Vec<usize> = (0..entries.len()).collect();
+    order.sort_by_key(|&i| entries[i]);
+    order
+}
+
+/// Number of whole bytes in the ordering of n palette entries, floor(log2(n!)) / 8
+fn order_bytes(entries: usize) -> usize {
+    let bits: f64 = (2..=entries).map(|n| (n as f64).log2()).sum();
+    bits.floor() as usize / 8
+}
+
+/// Maximum payload size in bytes that fits in the order of the palette entries
+pub fn capacity(png: &Png) -> Result<usize, Error> {
+    Ok(order_bytes(entries(png)?.len()).saturating_sub(HEADER_SIZE))
+}
+
+/// Hides the payload in the order of the palette entries
+///
+/// The payload and its length are read as one big number, written in the factorial number
+/// system and turned into a permutation of the entries sorted by color. Pixel indices, tRNS,
+/// hIST and bKGD are remapped to the new order, so the image looks the same.
+pub fn embed(png: &mut Png, payload: &[u8]) -> Result<(), Error> {
+    let entries = entries(png)?;
+    let capacity = order_bytes(entries.len()).saturating_sub(HEADER_SIZE);
+    ensure!(
+        payload.len() <= capacity,
+        "Payload is too long, the palette can hold at most {} bytes",
+        capacity
+    );
+
+    let mut number = vec![0; order_bytes(entries.len())];
+    number[..HEADER_SIZE].copy_from_slice(&(payload.len() as u16).to_be_bytes());
+    number[HEADER_SIZE..HEADER_SIZE + payload.len()].copy_from_slice(payload);
+
+    // Digit k is between 0 and k - 1 and picks one of the k entries that are left
+    let digits: Vec<usize> = (1..=entries.len())
+        .map(|k| divide(&mut number, k))
+        .collect();
+    let mut available = canonical_order(&entries);
+    let order: Vec<usize> = digits
+        .iter()
+        .rev()
+        .map(|&digit| available.remove(digit))
+        .collect();
+
+    reorder(png, &entries, &order)
+}
+
+/// Reads a payload written by [`embed`] from the order of the palette entries
+pub fn extract(png: &Png) -> Result<Vec<u8>, Error> {
+    let entries = entries(png)?;
+    let mut available = canonical_order(&entries);
+    let mut number = vec![0; order_bytes(entries.len())];
+
+    for (i, k) in (1..=entries.len()).rev().enumerate() {
+        let digit = available
+            .iter()
+            .position(|&entry| entry == i)
+            .expect("Every entry is in the canonical order");
+        available.remove(digit);
+        if !multiply_add(&mut number, k, digit) {
+            bail!("Palette order does not hold a message");
+        }
+    }
+
+    ensure!(number.len() >= HEADER_SIZE, "Palette is too small");
+    let len = u16::from_be_bytes([number[0], number[1]]) as usize;
+    ensure!(
+        len <= number.len() - HEADER_SIZE,
+        "Palette order does not hold a message"
+    );
+
+    Ok(number[HEADER_SIZE..HEADER_SIZE + len].to_vec())
+}
+
+/// Divides the big-endian number in place and returns the remainder
+fn divide(number: &mut [u8], divisor: usize) -> usize {
+    number.iter_mut().fold(0, |remainder, byte| {
+        let value = remainder << 8 | *byte as usize;
+        *byte = (value / divisor) as u8;
+        value % divisor
+    })
+}
+
+/// Multiplies the big-endian number in place and adds a value, false if it overflows
+fn multiply_add(number: &mut [u8], factor: usize, value: usize) -> bool {
+    let carry = number.iter_mut().rev().fold(value, |carry, byte| {
+        let value = *byte as usize * factor + carry;
+        *byte = value as u8;
+        value >> 8
+    });
+
+    carry == 0
+}
+
+/// Moves the palette entry `order[i]` to index i and remaps everything that refers to entries
+fn reorder(png: &mut Png, entries: &[[u8; 4]], order: &[usize]) -> Result<(), Error> {
+    let mut new_index = vec![0; order.len()];
+    order
+        .iter()
+        .enumerate()
+        .for_each(|(new, &old)| new_index[old] = new as u8);
+
+    let mut image = png.raw_image()?;
+    remap_pixels(&mut image, &new_index)?;
+    let options = EncodeOptions {
+        interlaced: png.ihdr()?.interlaced,
+        ..EncodeOptions::default()
+    };
+    png.replace_image(&image, &options)?;
+
+    for chunk in png.chunks.iter_mut() {
+        let data = match &chunk.r#type.bytes() {
+            b"PLTE" => order
+                .iter()
+                .flat_map(|&i| entries[i][..3].to_vec())
+                .collect(),
+            b"tRNS" => {
+                let mut alpha: Vec<u8> = order.iter().map(|&i| entries[i][3]).collect();
+                while alpha.len() > 1 && alpha.last() == Some(&255) {
+                    alpha.pop();
+                }
+                alpha
+            }
+            b"hIST" => {
+                ensure!(
+                    chunk.data.len() == 2 * order.len(),
+                    "hIST chunk does not match the palette"
+                );
+                order
+                    .iter()
+                    .flat_map(|&i| chunk.data[2 * i..2 * i + 2].to_vec())
+                    .collect()
+            }
+            b"bKGD" => {
+                let index = *chunk.data.first().context("bKGD chunk is empty")?;
+                vec![*new_index
+                    .get(index as usize)
+                    .context("bKGD index is outside of the palette")?]
+            }
+            _ => continue,
+        };
+        *chunk = Chunk::new(chunk.r#type.clone(), data);
+    }
+
+    Ok(())
+}
+
+/// Replaces every palette index in the packed scanlines with its new index
+fn remap_pixels(image: &mut RawImage, new_index: &[u8]) -> Result<(), Error> {
+    let depth = image.bit_depth as usize;
+    let width = image.width as usize;
+    let stride = image.ihdr(false).stride(width);
+    let mask = ((1u16 << depth) - 1) as u8;
+
+    for row in image.data.chunks_mut(stride) {
+        for bit in (0..width).map(|x| x * depth) {
+            let shift = 8 - depth - bit % 8;
+            let byte = &mut row[bit / 8];
+            let index = (*byte >> shift) & mask;
+            let new = *new_index
+                .get(index as usize)
+                .context("Pixel index is outside of the palette")?;
+            *byte = (*byte & !(mask << shift)) | (new << shift);
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use crate::chunk_type::ChunkType;
+
+    use super::*;
+
+    /// Indexed image whose palette entry i is (i, 2i, 3i) modulo 256 with alpha 255 - i
+    fn indexed_png(bit_depth: u8, colors: usize) -> Png {
+        let (width, height) = (16, 8);
+        let depth = bit_depth as usize;
+        let mut image = RawImage {
+            width: width as u32,
+            height: height as u32,
+            bit_depth,
+            color_type: ColorType::Indexed,
+            data: vec![],
+        };
+        let stride = image.ihdr(false).stride(width);
+        image.data = vec![0; stride * height];
+        for y in 0..height {
+            for x in 0..width {
+                let index = ((y * width + x) % colors) as u8;
+                image.data[y * stride + x * depth / 8] |= index << (8 - depth - x * depth % 8);
+            }
+        }
+
+        let chunk =
+            |name: &str, data: Vec<u8>| Chunk::new(ChunkType::from_str(name).unwrap(), data);
+        Png {
+            chunks: vec![
+                image.ihdr(false).as_chunk(),
+                chunk(
+                    "PLTE",
+                    (0..colors)
+                        .flat_map(|i| [i, 2 * i, 3 * i].map(|value| value as u8))
+                        .collect(),
+                ),
+                chunk("tRNS", (0..colors).map(|i| 255 - i as u8).collect()),
+                chunk(
+                    "hIST",
+                    (0..colors).flat_map(|i| (i as u16).to_be_bytes()).collect(),
+                ),
+                chunk("bKGD", vec![(colors - 1) as u8]),
+                chunk("IDAT", image.encode(&EncodeOptions::default()).unwrap()),
+                chunk("IEND", vec![]),
+            ],
+            trailer: Vec::new(),
+        }
+    }
+
+    /// RGBA color of every pixel
+    fn colors(png: &Png) -> Vec<[u8; 4]> {
+        let entries = entries(png).unwrap();
+        let image = png.raw_image().unwrap();
+        let depth = image.bit_depth as usize;
+        let stride = image.ihdr(false).stride(image.width as usize);
+
+        image
+            .data
+            .chunks(stride)
+            .flat_map(|row| {
+                (0..image.width as usize).map(move |x| {
+                    let shift = 8 - depth - (x * depth) % 8;
+                    (row[x * depth / 8] >> shift) & ((1u16 << depth) - 1) as u8
+                })
+            })
+            .map(|index| entries[index as usize])
+            .collect()
+    }
+
+    #[test]
+    fn test_embed_extract() {
+        let mut png = indexed_png(8, 200);
+        let before = colors(&png);
+        let palette = png.chunk_by_type("PLTE").unwrap().data.clone();
+        let payload = b"Palette order hides this message";
+
+        embed(&mut png, payload).unwrap();
+        assert_eq!(extract(&png).unwrap(), payload);
+        assert_eq!(colors(&png), before);
+        assert_ne!(png.chunk_by_type("PLTE").unwrap().data, palette);
+
+        // hIST and bKGD follow their entries
+        let entries = entries(&png).unwrap();
+        let hist = &png.chunk_by_type("hIST").unwrap().data;
+        for (i, entry) in entries.iter().enumerate() {
+            assert_eq!(
+                u16::from_be_bytes([hist[2 * i], hist[2 * i + 1]]),
+                entry[0] as u16
+            );
+        }
+        let background = png.chunk_by_type("bKGD").unwrap().data[0] as usize;
+        assert_eq!(entries[background][0], 199);
+    }
+
+    #[test]
+    fn test_embed_extract_packed() {
+        let mut png = indexed_png(4, 16);
+        let before = colors(&png);
+        let payload = &b"abc"[..capacity(&png).unwrap()];
+
+        embed(&mut png, payload).unwrap();
+        assert_eq!(extract(&png).unwrap(), payload);
+        assert_eq!(colors(&png), before);
+    }
+
+    #[test]
+    fn test_embed_too_long() {
+        let mut png = indexed_png(4, 16);
+        let payload = vec![1; capacity(&png).unwrap() + 1];
+        assert!(embed(&mut png, &payload).is_err());
+    }
+
+    #[test]
+    fn test_capacity() {
+        // log2(256!) is about 1684
+        assert_eq!(
+            capacity(&indexed_png(8, 256)).unwrap(),
+            1684 / 8 - HEADER_SIZE
+        );
+        // log2(16!) is about 44
+        assert_eq!(capacity(&indexed_png(4, 16)).unwrap(), 44 / 8 - HEADER_SIZE);
+    }
+
+    #[test]
+    fn test_duplicate_entries() {
+        let mut png = indexed_png(8, 4);
+        png.remove_chunk("tRNS").unwrap();
+        png.remove_chunk("PLTE").unwrap();
+        png.chunks.insert(
+            1,
+            Chunk::new(ChunkType::from_str("PLTE").unwrap(), vec![1, 2, 3, 1, 2, 3]),
+        );
+        assert!(capacity(&png).is_err());
+    }
+
+    #[test]
+    fn test_divide_multiply_add() {
+        let mut number = vec![1, 0, 7];
+        let remainder = divide(&mut number, 10);
+        assert!(multiply_add(&mut number, 10, remainder));
+        assert_eq!(number, vec![1, 0, 7]);
+        assert!(!multiply_add(&mut number, 256, 0));
+    }
+}
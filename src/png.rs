@@ -5,11 +5,15 @@ use std::path::PathBuf;
 use anyhow::{ensure, Context, Error};
 
 use crate::chunk::Chunk;
+use crate::chunk_type::ChunkType;
+use crate::image::{EncodeOptions, Ihdr, RawImage};
 use crate::util::slice_4_bytes;
 
 #[derive(Clone)]
 pub struct Png {
     pub chunks: Vec<Chunk>,
+    /// Bytes after the IEND chunk, kept as they are
+    pub trailer: Vec<u8>,
 }
 
 impl From<&PathBuf> for Png {
@@ -24,21 +28,31 @@ impl TryFrom<&[u8]> for Png {
     type Error = Error;
 
     fn try_from(value: &[u8]) -> Result<Self, Error> {
+        ensure!(value.len() >= 8, "File is too short to be a PNG");
         ensure!(
             <[u8; 8]>::try_from(&value[0..8])? == Png::STANDARD_HEADER,
             "Invalid PNG file signature: first eight bytes are not [137 80 78 71 13 10 26 10] (decimal)"
         );
 
-        let mut chunks = Vec::new();
+        let mut chunks: Vec<Chunk> = Vec::new();
         let mut cur = 8;
 
-        while cur < value.len() {
-            let length = u32::from_be_bytes(slice_4_bytes(value, cur)?);
-            chunks.push(Chunk::try_from(&value[cur..cur + length as usize + 12])?);
-            cur += length as usize + 12;
+        while cur < value.len()
+            && !chunks
+                .last()
+                .is_some_and(|chunk| chunk.r#type == ChunkType::IEND)
+        {
+            ensure!(cur + 4 <= value.len(), "Chunk at byte {} is truncated", cur);
+            let end = cur + u32::from_be_bytes(slice_4_bytes(value, cur)?) as usize + 12;
+            ensure!(end <= value.len(), "Chunk at byte {} is truncated", cur);
+            chunks.push(Chunk::try_from(&value[cur..end])?);
+            cur = end;
         }
 
-        Ok(Png { chunks })
+        Ok(Png {
+            chunks,
+            trailer: value[cur..].to_vec(),
+        })
     }
 }
 
@@ -58,9 +72,28 @@ impl Display for Png {
 
 impl Png {
     pub const STANDARD_HEADER: [u8; 8] = [137, 80, 78, 71, 13, 10, 26, 10];
+    pub const MAX_CHUNK_LENGTH: usize = (1 << 31) - 1;
 
+    /// Puts the data after IEND, refusing to replace trailing data unless `overwrite`
+    pub fn set_trailer(&mut self, trailer: Vec<u8>, overwrite: bool) -> Result<(), Error> {
+        ensure!(
+            overwrite || self.trailer.is_empty(),
+            "{} bytes already follow IEND",
+            self.trailer.len()
+        );
+        self.trailer = trailer;
+
+        Ok(())
+    }
+
+    /// Adds the chunk before IEND, or at the end if there is no IEND chunk
     pub fn append_chunk(&mut self, chunk: Chunk) {
-        self.chunks.push(chunk);
+        let end = self
+            .chunks
+            .iter()
+            .position(|chunk| chunk.r#type == ChunkType::IEND)
+            .unwrap_or(self.chunks.len());
+        self.chunks.insert(end, chunk);
     }
 
     pub fn remove_chunk(&mut self, chunk_type: &str) -> Result<Chunk, Error> {
@@ -79,17 +112,89 @@ impl Png {
             .find(|&chunk| chunk.r#type.to_string() == chunk_type)
     }
 
+    pub fn ihdr(&self) -> Result<Ihdr, Error> {
+        Ihdr::try_from(
+            self.chunk_by_type("IHDR")
+                .context("IHDR chunk does not exist")?,
+        )
+    }
+
+    /// Concatenated data of all IDAT chunks, the compressed image data stream
+    pub fn idat_data(&self) -> Vec<u8> {
+        self.chunks
+            .iter()
+            .filter(|chunk| chunk.r#type == ChunkType::IDAT)
+            .flat_map(|chunk| chunk.data.iter().copied())
+            .collect()
+    }
+
+    pub fn raw_image(&self) -> Result<RawImage, Error> {
+        RawImage::decode(&self.ihdr()?, &self.idat_data())
+    }
+
+    /// Re-encodes the image into new IDAT chunks in place of the existing ones
+    pub fn replace_image(
+        &mut self,
+        image: &RawImage,
+        options: &EncodeOptions,
+    ) -> Result<(), Error> {
+        let data = image.encode(options)?;
+        let header = self
+            .chunks
+            .iter()
+            .position(|chunk| chunk.r#type == ChunkType::IHDR)
+            .context("IHDR chunk does not exist")?;
+
+        self.chunks[header] = image.ihdr(options.interlaced).as_chunk();
+        self.set_idat_data(&data, options.idat_size)
+    }
+
+    /// Merges all IDAT chunks into one, without re-compressing the image data
+    pub fn coalesce_idat(&mut self) -> Result<(), Error> {
+        self.split_idat(Png::MAX_CHUNK_LENGTH)
+    }
+
+    /// Splits the image data into IDAT chunks of at most `max_len` bytes, without re-compressing it
+    pub fn split_idat(&mut self, max_len: usize) -> Result<(), Error> {
+        self.set_idat_data(&self.idat_data(), max_len)
+    }
+
+    fn set_idat_data(&mut self, data: &[u8], max_len: usize) -> Result<(), Error> {
+        ensure!(
+            (1..=Png::MAX_CHUNK_LENGTH).contains(&max_len),
+            "IDAT chunk size must be between 1 and {} bytes",
+            Png::MAX_CHUNK_LENGTH
+        );
+        ensure!(!data.is_empty(), "Image data is empty");
+
+        let position = self
+            .chunks
+            .iter()
+            .position(|chunk| chunk.r#type == ChunkType::IDAT)
+            .context("IDAT chunk does not exist")?;
+
+        self.chunks.retain(|chunk| chunk.r#type != ChunkType::IDAT);
+        self.chunks.splice(
+            position..position,
+            data.chunks(max_len)
+                .map(|data| Chunk::new(ChunkType::IDAT, data.to_vec())),
+        );
+
+        Ok(())
+    }
+
     pub fn as_bytes(&self) -> Vec<u8> {
         Png::STANDARD_HEADER
             .iter()
             .copied()
             .chain(self.chunks.iter().flat_map(|chunk| chunk.as_bytes()))
+            .chain(self.trailer.iter().copied())
             .collect()
     }
 }
 
 #[cfg(test)]
-mod tests {
+pub(crate) mod tests {
     use std::convert::TryFrom;
 
     use crate::chunk::Chunk;
@@ -107,7 +212,10 @@ mod tests {
 
     fn testing_png() -> Png {
         let chunks = testing_chunks();
-        Png { chunks }
+        Png {
+            chunks,
+            trailer: Vec::new(),
+        }
     }
 
     fn chunk_from_strings(chunk_type: &str, data: &str) -> Result<Chunk, Error> {
@@ -122,7 +230,10 @@ mod tests {
     #[test]
     fn test_from_chunks() {
         let chunks = testing_chunks();
-        let png = Png { chunks };
+        let png = Png {
+            chunks,
+            trailer: Vec::new(),
+        };
 
         assert_eq!(png.chunks.len(), 3);
     }
@@ -232,6 +343,93 @@ mod tests {
         assert_eq!(actual, expected);
     }
 
+    #[test]
+    fn test_split_idat() {
+        let mut png = Png::try_from(&PNG_FILE[..]).unwrap();
+        let data = png.idat_data();
+        png.split_idat(1000).unwrap();
+
+        let idats: Vec<&Chunk> = png
+            .chunks
+            .iter()
+            .filter(|chunk| chunk.r#type == ChunkType::IDAT)
+            .collect();
+        assert_eq!(idats.len(), data.len().div_ceil(1000));
+        assert!(idats.iter().all(|chunk| chunk.length <= 1000));
+        assert_eq!(png.idat_data(), data);
+        assert_eq!(png.chunks.last().unwrap().r#type.to_string(), "IEND");
+
+        let bytes = png.as_bytes();
+        assert!(Png::try_from(bytes.as_slice()).is_ok());
+    }
+
+    #[test]
+    fn test_coalesce_idat() {
+        let mut png = Png::try_from(&PNG_FILE[..]).unwrap();
+        png.split_idat(100).unwrap();
+        png.coalesce_idat().unwrap();
+        assert_eq!(png.as_bytes(), PNG_FILE.to_vec());
+    }
+
+    #[test]
+    fn test_split_idat_zero_size() {
+        let mut png = Png::try_from(&PNG_FILE[..]).unwrap();
+        assert!(png.split_idat(0).is_err());
+    }
+
+    #[test]
+    fn test_trailer() {
+        let bytes = [&PNG_FILE[..], b"after IEND"].concat();
+        let png = Png::try_from(bytes.as_slice()).unwrap();
+
+        assert_eq!(png.trailer, b"after IEND");
+        assert_eq!(png.chunks.last().unwrap().r#type, ChunkType::IEND);
+        assert_eq!(png.as_bytes(), bytes);
+    }
+
+    #[test]
+    fn test_set_trailer() {
+        let bytes = [&PNG_FILE[..], b"PK\x03\x04"].concat();
+        let mut png = Png::try_from(bytes.as_slice()).unwrap();
+
+        assert!(png.set_trailer(b"message".to_vec(), false).is_err());
+        assert_eq!(png.trailer, b"PK\x03\x04");
+        png.set_trailer(b"message".to_vec(), true).unwrap();
+        assert_eq!(png.trailer, b"message");
+    }
+
+    #[test]
+    fn test_append_chunk_before_iend() {
+        let mut png = Png::try_from(&PNG_FILE[..]).unwrap();
+        png.append_chunk(chunk_from_strings("TeSt", "Message").unwrap());
+
+        let png = Png::try_from(png.as_bytes().as_slice()).unwrap();
+        assert!(png.chunk_by_type("TeSt").is_some());
+        assert!(png.trailer.is_empty());
+    }
+
+    #[test]
+    fn test_truncated_chunk() {
+        assert!(Png::try_from(&PNG_FILE[..PNG_FILE.len() - 4]).is_err());
+    }
+
+    #[test]
+    fn test_stray_bytes_without_iend() {
+        // Every chunk but IEND, then 1 to 3 bytes that cannot hold a chunk length
+        let without_iend = &PNG_FILE[..PNG_FILE.len() - 12];
+        for stray in 1..=3 {
+            let bytes = [without_iend, &[0; 3][..stray]].concat();
+            assert!(Png::try_from(bytes.as_slice()).is_err());
+        }
+    }
+
+    #[test]
+    fn test_too_short() {
+        for len in 0..8 {
+            assert!(Png::try_from(&PNG_FILE[..len]).is_err());
+        }
+    }
+
     #[test]
     fn test_png_trait_impls() {
         let chunk_bytes: Vec<u8> = testing_chunks()
@@ -251,7 +449,7 @@ mod tests {
     }
 
     // This is the raw bytes for a shrunken version of the `dice.png` image on Wikipedia
-    const PNG_FILE: [u8; 4803] = [
+    pub(crate) const PNG_FILE: [u8; 4803] = [
         137, 80, 78, 71, 13, 10, 26, 10, 0, 0, 0, 13, 73, 72, 68, 82, 0, 0, 0, 50, 0, 0, 0, 50, 8,
         6, 0, 0, 0, 30, 63, 136, 177, 0, 0, 0, 1, 115, 82, 71, 66, 0, 174, 206, 28, 233, 0, 0, 0,
         4, 103, 65, 77, 65, 0, 0, 177, 143, 11, 252, 97, 5, 0, 0, 0, 9, 112, 72, 89, 115, 0, 0, 14,
@@ -0,0 +1,254 @@
+use std::fmt;
+use std::fmt::{Display, Formatter};
+use std::fs;
+use std::path::Path;
+use std::str::FromStr;
+
+use anyhow::{anyhow, ensure, Context, Error, Result};
+use chacha20poly1305::aead::{Aead, KeyInit, Payload};
+use chacha20poly1305::{Key, XChaCha20Poly1305, XNonce};
+use hkdf::Hkdf;
+use rand::RngCore;
+use sha2::Sha256;
+use x25519_dalek::{PublicKey, StaticSecret};
+
+use crate::util::{decode_key, encode_key, key_lines};
+
+const PUBLIC_KEY_HRP: &str = "pngme";
+const SECRET_KEY_HRP: &str = "PNGME-SECRET-KEY-";
+
+/// Domain separation of the keys that wrap the file key
+const WRAP_INFO: &[u8] = b"pngme-x25519";
+
+const KEY_SIZE: usize = 32;
+const NONCE_SIZE: usize = 24;
+const TAG_SIZE: usize = 16;
+
+/// Ephemeral public key and wrapped file key for one recipient
+pub const STANZA_SIZE: usize = KEY_SIZE + KEY_SIZE + TAG_SIZE;
+
+/// Bytes added to a message besides the stanzas: recipient count, nonce and authentication tag
+pub const OVERHEAD: usize = 1 + NONCE_SIZE + TAG_SIZE;
+
+/// Public key that messages are encrypted to, written as `pngme1...`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Recipient(PublicKey);
+
+/// Secret key that decrypts messages, written as `PNGME-SECRET-KEY-1...`
+pub struct Identity(StaticSecret);
+
+impl Identity {
+    pub fn generate() -> Identity {
+        Identity(StaticSecret::random_from_rng(rand::thread_rng()))
+    }
+
+    pub fn recipient(&self) -> Recipient {
+        Recipient(PublicKey::from(&self.0))
+    }
+
+    /// Reads every identity of a file, skipping blank lines and `#` comments
+    pub fn read_file(path: &Path) -> Result<Vec<Identity>, Error> {
+        let text = fs::read_to_string(path)
+            .with_context(|| format!("Cannot read identity file {}", path.display()))?;
+        let identities = key_lines(&text)
+            .map(Identity::from_str)
+            .collect::<Result<Vec<_>, Error>>()?;
+        ensure!(!identities.is_empty(), "Identity file holds no secret key");
+
+        Ok(identities)
+    }
+
+    /// Contents of an identity file, with the public key as a comment
+    pub fn to_file_string(&self) -> String {
+        format!("# public key: {}\n{}\n", self.recipient(), self)
+    }
+}
+
+impl FromStr for Recipient {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Error> {
+        Ok(Recipient(PublicKey::from(decode_key(s, PUBLIC_KEY_HRP)?)))
+    }
+}
+
+impl FromStr for Identity {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Error> {
+        Ok(Identity(StaticSecret::from(decode_key(s, SECRET_KEY_HRP)?)))
+    }
+}
+
+impl Display for Recipient {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}",
+            encode_key(PUBLIC_KEY_HRP, self.0.as_bytes(), false)
+        )
+    }
+}
+
+impl Display for Identity {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", encode_key(SECRET_KEY_HRP, self.0.as_bytes(), true))
+    }
+}
+
+/// Key that wraps the file key for one recipient, from a Diffie-Hellman exchange with an
+/// ephemeral key
+fn wrap_key(
+    shared: &[u8; KEY_SIZE],
+    ephemeral: &PublicKey,
+    recipient: &PublicKey,
+) -> Result<Key, Error> {
+    let salt = [&ephemeral.as_bytes()[..], recipient.as_bytes()].concat();
+    let mut key = Key::default();
+    Hkdf::<Sha256>::new(Some(&salt), shared)
+        .expand(WRAP_INFO, &mut key)
+        .map_err(|_| anyhow!("Cannot derive wrapping key"))?;
+
+    Ok(key)
+}
+
+/// Encrypts the message with a random file key that is wrapped for every recipient
+///
+/// The data starts with the number of recipients and one stanza per recipient, followed by
+/// the nonce and the ciphertext, which authenticates everything before it.
+pub fn encrypt(message: &[u8], recipients: &[Recipient]) -> Result<Vec<u8>, Error> {
+    ensure!(
+        (1..=u8::MAX as usize).contains(&recipients.len()),
+        "Messages are encrypted to between 1 and {} recipients",
+        u8::MAX
+    );
+
+    let mut rng = rand::thread_rng();
+    let mut file_key = Key::default();
+    rng.fill_bytes(&mut file_key);
+
+    let mut header = vec![recipients.len() as u8];
+    for Recipient(recipient) in recipients {
+        let secret = StaticSecret::random_from_rng(&mut rng);
+        let ephemeral = PublicKey::from(&secret);
+        let shared = secret.diffie_hellman(recipient);
+        ensure!(shared.was_contributory(), "Recipient key is invalid");
+
+        // Every wrapping key is used once, so a zero nonce is safe
+        let wrapped = XChaCha20Poly1305::new(&wrap_key(shared.as_bytes(), &ephemeral, recipient)?)
+            .encrypt(&XNonce::default(), file_key.as_slice())
+            .map_err(|_| anyhow!("Cannot wrap file key"))?;
+        header.extend_from_slice(ephemeral.as_bytes());
+        header.extend_from_slice(&wrapped);
+    }
+
+    let mut nonce = XNonce::default();
+    rng.fill_bytes(&mut nonce);
+    header.extend_from_slice(&nonce);
+    let ciphertext = XChaCha20Poly1305::new(&file_key)
+        .encrypt(
+            &nonce,
+            Payload {
+                msg: message,
+                aad: &header,
+            },
+        )
+        .map_err(|_| anyhow!("Cannot encrypt message"))?;
+
+    Ok([header, ciphertext].concat())
+}
+
+/// Decrypts a message made by [`encrypt`] with the first identity it was encrypted to
+pub fn decrypt(data: &[u8], identities: &[Identity]) -> Result<Vec<u8>, Error> {
+    let count = *data.first().context("Data is empty")? as usize;
+    let header_size = 1 + count * STANZA_SIZE + NONCE_SIZE;
+    ensure!(
+        count > 0 && data.len() >= OVERHEAD + count * STANZA_SIZE,
+        "Data is not encrypted to recipients"
+    );
+    let (header, ciphertext) = data.split_at(header_size);
+    let stanzas = &header[1..header_size - NONCE_SIZE];
+
+    let file_key = identities
+        .iter()
+        .flat_map(|Identity(secret)| {
+            stanzas.chunks(STANZA_SIZE).map(move |stanza| {
+                let ephemeral = PublicKey::from(<[u8; KEY_SIZE]>::try_from(&stanza[..KEY_SIZE])?);
+                let shared = secret.diffie_hellman(&ephemeral);
+                let key = wrap_key(shared.as_bytes(), &ephemeral, &PublicKey::from(secret))?;
+
+                XChaCha20Poly1305::new(&key)
+                    .decrypt(&XNonce::default(), &stanza[KEY_SIZE..])
+                    .map_err(|_| anyhow!("Stanza belongs to another recipient"))
+            })
+        })
+        .find_map(Result::ok)
+        .context("Message is not encrypted to this identity")?;
+    ensure!(file_key.len() == KEY_SIZE, "File key is invalid");
+
+    XChaCha20Poly1305::new(Key::from_slice(&file_key))
+        .decrypt(
+            XNonce::from_slice(&header[header_size - NONCE_SIZE..]),
+            Payload {
+                msg: ciphertext,
+                aad: header,
+            },
+        )
+        .map_err(|_| anyhow!("Message was tampered with"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_key_strings() {
+        let identity = Identity::generate();
+        let recipient = identity.recipient();
+
+        assert!(recipient.to_string().starts_with("pngme1"));
+        assert!(identity.to_string().starts_with("PNGME-SECRET-KEY-1"));
+        assert_eq!(
+            Recipient::from_str(&recipient.to_string()).unwrap(),
+            recipient
+        );
+        let parsed = Identity::from_str(&identity.to_string()).unwrap();
+        assert_eq!(parsed.recipient(), recipient);
+
+        assert!(Recipient::from_str(&identity.to_string()).is_err());
+        assert!(Identity::from_str(&recipient.to_string()).is_err());
+        let mut typo = recipient.to_string();
+        typo.replace_range(10..11, if &typo[10..11] == "q" { "p" } else { "q" });
+        assert!(Recipient::from_str(&typo).is_err());
+    }
+
+    #[test]
+    fn test_multiple_recipients() {
+        let alice = Identity::generate();
+        let bob = Identity::generate();
+        let data = encrypt(b"Team meeting", &[alice.recipient(), bob.recipient()]).unwrap();
+        assert_eq!(data.len(), OVERHEAD + 2 * STANZA_SIZE + 12);
+
+        assert_eq!(decrypt(&data, &[alice]).unwrap(), b"Team meeting");
+        assert_eq!(
+            decrypt(&data, &[Identity::generate(), bob]).unwrap(),
+            b"Team meeting"
+        );
+        assert_eq!(
+            decrypt(&data, &[Identity::generate()])
+                .unwrap_err()
+                .to_string(),
+            "Message is not encrypted to this identity"
+        );
+    }
+
+    #[test]
+    fn test_tampered() {
+        let identity = Identity::generate();
+        let mut data = encrypt(b"Team meeting", &[identity.recipient()]).unwrap();
+        let last = data.len() - 1;
+        data[last] ^= 1;
+        assert!(decrypt(&data, &[identity]).is_err());
+        assert!(encrypt(b"Team meeting", &[]).is_err());
+    }
+}
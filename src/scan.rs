@@ -0,0 +1,708 @@
+use std::fmt;
+use std::fmt::{Display, Formatter};
+
+use anyhow::{Error, Result};
+use serde::Serialize;
+
+use crate::chunk::Chunk;
+use crate::image::{ColorType, RawImage};
+use crate::png::Png;
+
+/// Chunk types of the PNG specification and its registered extensions
+const KNOWN_CHUNK_TYPES: [&str; 31] = [
+    "IHDR", "PLTE", "IDAT", "IEND", "tRNS", "cHRM", "gAMA", "iCCP", "sBIT", "sRGB", "cICP", "mDCV",
+    "cLLI", "tEXt", "zTXt", "iTXt", "bKGD", "hIST", "pHYs", "sPLT", "eXIf", "tIME", "acTL", "fcTL",
+    "fdAT", "oFFs", "pCAL", "sCAL", "sTER", "gIFg", "gIFx",
+];
+
+/// Signatures of files that are commonly appended to images to make polyglots
+const TRAILER_SIGNATURES: [(&[u8], &str); 6] = [
+    (b"PK\x03\x04", "a ZIP archive"),
+    (b"Rar!", "a RAR archive"),
+    (b"7z\xBC\xAF", "a 7z archive"),
+    (b"%PDF", "a PDF document"),
+    (b"\x89PNG", "another PNG image"),
+    (b"\xFF\xD8\xFF", "a JPEG image"),
+];
+
+const TEXT_CHUNK_TYPES: [&str; 3] = ["tEXt", "zTXt", "iTXt"];
+
+/// Ancillary chunks that hold compressed data, so their high entropy is expected
+const COMPRESSED_CHUNK_TYPES: [&str; 3] = ["zTXt", "iTXt", "iCCP"];
+
+/// Text chunks larger than this are unusual for ordinary metadata
+const OVERSIZED_TEXT: usize = 2048;
+
+/// Ancillary chunks smaller than this are too short for a meaningful entropy
+const ENTROPY_MIN_SIZE: usize = 256;
+
+/// Bits per byte above which data looks encrypted or compressed
+const ENTROPY_THRESHOLD: f64 = 7.5;
+
+/// Chi-square p-value above which the pairs of values look equalized by LSB embedding
+const CHI_SQUARE_THRESHOLD: f64 = 0.95;
+
+/// Embedding rates estimated by RS analysis that are reported as medium and high risk
+const RS_THRESHOLDS: (f64, f64) = (0.15, 0.35);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    Info,
+    Low,
+    Medium,
+    High,
+}
+
+impl Severity {
+    fn weight(&self) -> u32 {
+        match self {
+            Severity::Info => 0,
+            Severity::Low => 10,
+            Severity::Medium => 25,
+            Severity::High => 50,
+        }
+    }
+}
+
+impl Display for Severity {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Severity::Info => "info",
+            Severity::Low => "low",
+            Severity::Medium => "medium",
+            Severity::High => "high",
+        };
+
+        write!(f, "{}", name)
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct Finding {
+    pub severity: Severity,
+    pub kind: &'static str,
+    pub detail: String,
+}
+
+impl Finding {
+    fn new(severity: Severity, kind: &'static str, detail: String) -> Finding {
+        Finding {
+            severity,
+            kind,
+            detail,
+        }
+    }
+}
+
+/// Signs of hidden data in a PNG file, with a risk score between 0 and 100
+#[derive(Debug, Serialize)]
+pub struct ScanReport {
+    pub risk_score: u32,
+    pub findings: Vec<Finding>,
+}
+
+impl ScanReport {
+    /// Scans the bytes of a file, reporting a file that cannot be parsed as a finding
+    pub fn from_bytes(bytes: &[u8]) -> Result<ScanReport, Error> {
+        match Png::try_from(bytes) {
+            Ok(png) => ScanReport::new(&png),
+            Err(error) => Ok(ScanReport::from_findings(vec![Finding::new(
+                Severity::High,
+                "malformed",
+                format!("Cannot parse PNG file: {}", error),
+            )])),
+        }
+    }
+
+    pub fn new(png: &Png) -> Result<ScanReport, Error> {
+        let mut findings = Vec::new();
+
+        if !png.trailer.is_empty() {
+            findings.push(Finding::new(
+                Severity::High,
+                "trailing-data",
+                format!(
+                    "{} bytes after the IEND chunk{}",
+                    png.trailer.len(),
+                    TRAILER_SIGNATURES
+                        .iter()
+                        .find(|(signature, _)| png.trailer.starts_with(signature))
+                        .map_or(String::new(), |(_, name)| format!(
+                            ", starting like {}",
+                            name
+                        ))
+                ),
+            ));
+        }
+
+        findings.extend(png.chunks.iter().flat_map(chunk_findings));
+
+        match png.raw_image() {
+            Ok(image) => findings.extend(pixel_findings(&image)),
+            Err(error) => findings.push(Finding::new(
+                Severity::Medium,
+                "malformed",
+                format!("Cannot decode image data: {}", error),
+            )),
+        }
+
+        Ok(ScanReport::from_findings(findings))
+    }
+
+    fn from_findings(mut findings: Vec<Finding>) -> ScanReport {
+        findings.sort_by_key(|finding| std::cmp::Reverse(finding.severity));
+
+        ScanReport {
+            risk_score: findings
+                .iter()
+                .map(|finding| finding.severity.weight())
+                .sum::<u32>()
+                .min(100),
+            findings,
+        }
+    }
+
+    pub fn to_json(&self) -> Result<String, Error> {
+        Ok(serde_json::to_string_pretty(self)?)
+    }
+}
+
+impl Display for ScanReport {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Risk score: {}/100", self.risk_score)?;
+        for finding in &self.findings {
+            writeln!(
+                f,
+                "[{}] {}: {}",
+                finding.severity, finding.kind, finding.detail
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+fn chunk_findings(chunk: &Chunk) -> Vec<Finding> {
+    let chunk_type = &chunk.r#type;
+    let name = String::from_utf8_lossy(&chunk_type.bytes()).into_owned();
+    let kind = if chunk_type.is_critical() {
+        "critical"
+    } else {
+        "ancillary"
+    };
+    let mut findings = Vec::new();
+
+    if !chunk_type.is_reserved_bit_valid() {
+        findings.push(Finding::new(
+            Severity::High,
+            "invalid-chunk-type",
+            format!("{} chunk has the reserved bit set", name),
+        ));
+    } else if !chunk_type.is_public() {
+        findings.push(Finding::new(
+            if chunk_type.is_critical() {
+                Severity::High
+            } else {
+                Severity::Medium
+            },
+            "private-chunk",
+            format!(
+                "{} is a private {} chunk with {} bytes of data",
+                name,
+                kind,
+                chunk.data.len()
+            ),
+        ));
+    } else if !KNOWN_CHUNK_TYPES.contains(&name.as_str()) {
+        findings.push(Finding::new(
+            if chunk_type.is_critical() {
+                Severity::High
+            } else {
+                Severity::Low
+            },
+            "unknown-chunk",
+            format!(
+                "{} is an unregistered public {} chunk{}",
+                name,
+                kind,
+                if chunk_type.is_safe_to_copy() {
+                    ", marked safe to copy"
+                } else {
+                    ""
+                }
+            ),
+        ));
+    }
+
+    if TEXT_CHUNK_TYPES.contains(&name.as_str()) && chunk.data.len() > OVERSIZED_TEXT {
+        findings.push(Finding::new(
+            Severity::Medium,
+            "oversized-text",
+            format!("{} chunk holds {} bytes", name, chunk.data.len()),
+        ));
+    }
+
+    if !chunk_type.is_critical()
+        && !COMPRESSED_CHUNK_TYPES.contains(&name.as_str())
+        && chunk.data.len() >= ENTROPY_MIN_SIZE
+    {
+        let entropy = entropy(&chunk.data);
+        if entropy > ENTROPY_THRESHOLD {
+            findings.push(Finding::new(
+                Severity::Medium,
+                "high-entropy",
+                format!(
+                    "{} chunk data has {:.2} bits of entropy per byte, it looks encrypted or compressed",
+                    name, entropy
+                ),
+            ));
+        }
+    }
+
+    findings
+}
+
+/// Shannon entropy in bits per byte
+fn entropy(data: &[u8]) -> f64 {
+    let mut counts = [0usize; 256];
+    data.iter().for_each(|&byte| counts[byte as usize] += 1);
+
+    counts
+        .iter()
+        .filter(|&&count| count > 0)
+        .map(|&count| {
+            let p = count as f64 / data.len() as f64;
+            -p * p.log2()
+        })
+        .sum()
+}
+
+fn channel_names(color_type: ColorType) -> &'static [&'static str] {
+    match color_type {
+        ColorType::Grayscale | ColorType::Indexed => &["Y"],
+        ColorType::GrayscaleAlpha => &["Y", "A"],
+        ColorType::Rgb => &["R", "G", "B"],
+        ColorType::Rgba => &["R", "G", "B", "A"],
+    }
+}
+
+/// Runs the chi-square and RS tests on the least significant bits of every channel
+fn pixel_findings(image: &RawImage) -> Vec<Finding> {
+    if image.color_type == ColorType::Indexed || image.bit_depth < 8 {
+        return vec![Finding::new(
+            Severity::Info,
+            "lsb-statistics",
+            String::from("Statistical tests only run on 8 and 16 bit non-indexed images"),
+        )];
+    }
+
+    let size = image.bit_depth as usize / 8;
+    let channels = image.color_type.channels();
+    let samples: Vec<i32> = image
+        .data
+        .chunks(size)
+        .map(|sample| {
+            sample
+                .iter()
+                .fold(0, |value, &byte| (value << 8) | byte as i32)
+        })
+        .collect();
+    let mut findings = Vec::new();
+
+    for (channel, name) in channel_names(image.color_type).iter().enumerate() {
+        let values: Vec<i32> = samples
+            .iter()
+            .skip(channel)
+            .step_by(channels)
+            .copied()
+            .collect();
+
+        // Sequential embedding of a short message only equalizes the start of the image
+        let chi_square = [values.len() / 10, values.len()]
+            .iter()
+            .filter_map(|&len| chi_square_p(&values[..len]))
+            .fold(None, |max: Option<f64>, p| {
+                Some(max.map_or(p, |max| max.max(p)))
+            });
+        let rs = rs_estimate(&values, image.width as usize);
+
+        if let Some(p) = chi_square.filter(|&p| p > CHI_SQUARE_THRESHOLD) {
+            findings.push(Finding::new(
+                Severity::High,
+                "chi-square",
+                format!(
+                    "Pairs of values in channel {} are equalized like after LSB embedding (p = {:.3})",
+                    name, p
+                ),
+            ));
+        }
+
+        if let Some(rate) = rs.filter(|&rate| rate > RS_THRESHOLDS.0) {
+            findings.push(Finding::new(
+                if rate > RS_THRESHOLDS.1 {
+                    Severity::High
+                } else {
+                    Severity::Medium
+                },
+                "rs-analysis",
+                format!(
+                    "RS analysis estimates that {:.0}% of the samples in channel {} carry data",
+                    rate * 100.0,
+                    name
+                ),
+            ));
+        }
+
+        findings.push(Finding::new(
+            Severity::Info,
+            "lsb-statistics",
+            format!(
+                "Channel {}: chi-square p = {}, RS embedding rate = {}",
+                name,
+                chi_square.map_or(String::from("n/a"), |p| format!("{:.3}", p)),
+                rs.map_or(String::from("n/a"), |rate| format!("{:.3}", rate))
+            ),
+        ));
+    }
+
+    findings
+}
+
+/// Probability that the pairs of values (2k, 2k + 1) are as equal as they are by chance
+///
+/// LSB embedding of random data makes both values of a pair equally likely, which a
+/// chi-square test of the even values against the pair averages detects (Westfeld and Pfitzmann).
+fn chi_square_p(values: &[i32]) -> Option<f64> {
+    let mut histogram = [0usize; 256];
+    values
+        .iter()
+        .for_each(|&value| histogram[(value & 0xFF) as usize] += 1);
+
+    let (chi_square, categories) = histogram
+        .chunks(2)
+        .map(|pair| (pair[0] as f64, (pair[0] + pair[1]) as f64 / 2.0))
+        .filter(|&(_, expected)| expected >= 5.0)
+        .fold((0.0, 0), |(sum, categories), (observed, expected)| {
+            (
+                sum + (observed - expected).powi(2) / expected,
+                categories + 1,
+            )
+        });
+
+    (categories >= 2).then(|| gamma_q((categories - 1) as f64 / 2.0, chi_square / 2.0))
+}
+
+/// Estimates the fraction of samples whose LSB carries data with RS analysis (Fridrich et al.)
+fn rs_estimate(values: &[i32], width: usize) -> Option<f64> {
+    let flipped: Vec<i32> = values.iter().map(|value| value ^ 1).collect();
+    let (r, s, r_neg, s_neg) = rs_groups(values, width)?;
+    let (r_flip, s_flip, r_neg_flip, s_neg_flip) = rs_groups(&flipped, width)?;
+
+    let d0 = r - s;
+    let d1 = r_flip - s_flip;
+    let d_neg0 = r_neg - s_neg;
+    let d_neg1 = r_neg_flip - s_neg_flip;
+
+    let a = 2.0 * (d1 + d0);
+    let b = d_neg0 - d_neg1 - d1 - 3.0 * d0;
+    let c = d0 - d_neg0;
+
+    let z = if a.abs() < 1e-12 {
+        if b.abs() < 1e-12 {
+            return None;
+        }
+        -c / b
+    } else {
+        let discriminant = b * b - 4.0 * a * c;
+        if discriminant < 0.0 {
+            return None;
+        }
+        let roots = [
+            (-b + discriminant.sqrt()) / (2.0 * a),
+            (-b - discriminant.sqrt()) / (2.0 * a),
+        ];
+        if roots[0].abs() < roots[1].abs() {
+            roots[0]
+        } else {
+            roots[1]
+        }
+    };
+
+    Some((z / (z - 0.5)).clamp(0.0, 1.0))
+}
+
+/// Fractions of regular and singular groups of four horizontal neighbours, flipped with the
+/// mask [0, 1, 1, 0] and with its negative
+fn rs_groups(values: &[i32], width: usize) -> Option<(f64, f64, f64, f64)> {
+    const MASK: [bool; 4] = [false, true, true, false];
+
+    let smoothness =
+        |group: &[i32]| -> i32 { group.windows(2).map(|pair| (pair[1] - pair[0]).abs()).sum() };
+    let flip = |group: &[i32], f: fn(i32) -> i32| -> Vec<i32> {
+        group
+            .iter()
+            .zip(MASK)
+            .map(|(&value, masked)| if masked { f(value) } else { value })
+            .collect()
+    };
+
+    let mut counts = [0usize; 4];
+    let mut total = 0;
+    for group in values.chunks(width).flat_map(|row| row.chunks_exact(4)) {
+        let original = smoothness(group);
+        let positive = smoothness(&flip(group, |value| value ^ 1));
+        let negative = smoothness(&flip(group, |value| ((value + 1) ^ 1) - 1));
+
+        counts[0] += (positive > original) as usize;
+        counts[1] += (positive < original) as usize;
+        counts[2] += (negative > original) as usize;
+        counts[3] += (negative < original) as usize;
+        total += 1;
+    }
+
+    (total > 0).then(|| {
+        let total = total as f64;
+        (
+            counts[0] as f64 / total,
+            counts[1] as f64 / total,
+            counts[2] as f64 / total,
+            counts[3] as f64 / total,
+        )
+    })
+}
+
+fn ln_gamma(x: f64) -> f64 {
+    const COEFFICIENTS: [f64; 6] = [
+        76.18009172947146,
+        -86.50532032941677,
+        24.01409824083091,
+        -1.231739572450155,
+        0.1208650973866179e-2,
+        -0.5395239384953e-5,
+    ];
+
+    let tmp = x + 5.5 - (x + 0.5) * (x + 5.5).ln();
+    let series = COEFFICIENTS
+        .iter()
+        .enumerate()
+        .fold(1.000000000190015, |sum, (i, c)| {
+            sum + c / (x + 1.0 + i as f64)
+        });
+
+    -tmp + (2.5066282746310005 * series / x).ln()
+}
+
+/// Regularized upper incomplete gamma function Q(a, x)
+fn gamma_q(a: f64, x: f64) -> f64 {
+    const EPSILON: f64 = 1e-12;
+    const TINY: f64 = 1e-300;
+
+    if x <= 0.0 {
+        return 1.0;
+    }
+
+    let prefix = (-x + a * x.ln() - ln_gamma(a)).exp();
+
+    if x < a + 1.0 {
+        // Series of the lower function P(a, x)
+        let mut term = 1.0 / a;
+        let mut sum = term;
+        for n in 1..1000 {
+            term *= x / (a + n as f64);
+            sum += term;
+            if term.abs() < sum.abs() * EPSILON {
+                break;
+            }
+        }
+
+        (1.0 - sum * prefix).clamp(0.0, 1.0)
+    } else {
+        // Continued fraction of Q(a, x) with the modified Lentz method
+        let mut b = x + 1.0 - a;
+        let mut c = 1.0 / TINY;
+        let mut d = 1.0 / b;
+        let mut h = d;
+        for i in 1..1000 {
+            let an = -(i as f64) * (i as f64 - a);
+            b += 2.0;
+            d = an * d + b;
+            if d.abs() < TINY {
+                d = TINY;
+            }
+            c = b + an / c;
+            if c.abs() < TINY {
+                c = TINY;
+            }
+            d = 1.0 / d;
+            let delta = d * c;
+            h *= delta;
+            if (delta - 1.0).abs() < EPSILON {
+                break;
+            }
+        }
+
+        (prefix * h).clamp(0.0, 1.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use crate::chunk_type::ChunkType;
+    use crate::image::EncodeOptions;
+    use crate::lsb;
+    use crate::lsb::LsbOptions;
+    use crate::png::tests::PNG_FILE;
+
+    use super::*;
+
+    fn has_finding(report: &ScanReport, kind: &str) -> bool {
+        report.findings.iter().any(|finding| finding.kind == kind)
+    }
+
+    /// Pseudorandom bytes that look like encrypted data
+    fn noise(len: usize) -> Vec<u8> {
+        let mut state: u32 = 12345;
+        (0..len)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 17;
+                state ^= state << 5;
+                (state >> 24) as u8
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_trailing_data() {
+        let mut png = Png::try_from(&PNG_FILE[..]).unwrap();
+        png.trailer = b"PK\x03\x04 appended archive".to_vec();
+        let report = ScanReport::new(&png).unwrap();
+        assert!(has_finding(&report, "trailing-data"));
+        assert!(report.findings[0].detail.ends_with("a ZIP archive"));
+        assert!(report.risk_score >= 50);
+    }
+
+    #[test]
+    fn test_private_chunk() {
+        // The test image has a private critical RuSt chunk
+        let report = ScanReport::new(&Png::try_from(&PNG_FILE[..]).unwrap()).unwrap();
+        assert!(has_finding(&report, "private-chunk"));
+        assert!(!has_finding(&report, "trailing-data"));
+    }
+
+    #[test]
+    fn test_hostile_ihdr() {
+        let mut png = Png::try_from(&PNG_FILE[..]).unwrap();
+        let mut data = png.chunks[0].data.clone();
+        data[..8].copy_from_slice(&[0x7f, 0xff, 0xff, 0xff, 0x7f, 0xff, 0xff, 0xff]);
+        png.chunks[0] = Chunk::new(ChunkType::from_str("IHDR").unwrap(), data);
+
+        let report = ScanReport::from_bytes(&png.as_bytes()).unwrap();
+        let finding = report
+            .findings
+            .iter()
+            .find(|finding| finding.kind == "malformed")
+            .unwrap();
+        assert!(finding.detail.starts_with("Cannot decode image data"));
+    }
+
+    #[test]
+    fn test_malformed_file() {
+        for bytes in [&PNG_FILE[..5], &PNG_FILE[..PNG_FILE.len() - 4]] {
+            let report = ScanReport::from_bytes(bytes).unwrap();
+            assert_eq!(report.findings[0].kind, "malformed");
+            assert_eq!(report.risk_score, 50);
+        }
+    }
+
+    #[test]
+    fn test_chunk_findings() {
+        let findings = chunk_findings(&Chunk::new(ChunkType::from_str("Rust").unwrap(), vec![]));
+        assert_eq!(findings[0].kind, "invalid-chunk-type");
+
+        let findings = chunk_findings(&Chunk::new(
+            ChunkType::from_str("ruSt").unwrap(),
+            noise(1024),
+        ));
+        assert_eq!(findings[0].kind, "private-chunk");
+        assert_eq!(findings[1].kind, "high-entropy");
+
+        let findings = chunk_findings(&Chunk::new(
+            ChunkType::from_str("tEXt").unwrap(),
+            vec![b'a'; 4096],
+        ));
+        assert_eq!(findings.len(), 1);
+        assert_eq!(findings[0].kind, "oversized-text");
+
+        let findings = chunk_findings(&Chunk::new(
+            ChunkType::from_str("zTXt").unwrap(),
+            noise(1024),
+        ));
+        assert!(findings.is_empty());
+    }
+
+    #[test]
+    fn test_entropy() {
+        assert_eq!(entropy(&[7; 100]), 0.0);
+        assert!((entropy(&(0..=255).collect::<Vec<u8>>()) - 8.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_gamma_q() {
+        // Chi-square survival function with 2 degrees of freedom is exp(-x / 2)
+        for x in [0.5, 2.0, 10.0] {
+            assert!((gamma_q(1.0, x / 2.0) - (-x / 2.0f64).exp()).abs() < 1e-9);
+        }
+        assert!((gamma_q(5.0, 3.0) - 0.8152632445237722).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_lsb_embedding_detected() {
+        let mut png = Png::try_from(&PNG_FILE[..]).unwrap();
+        let clean = ScanReport::new(&png).unwrap();
+        assert!(!has_finding(&clean, "chi-square"));
+
+        let mut image = png.raw_image().unwrap();
+        let options = LsbOptions::default();
+        let payload = noise(lsb::capacity(&image, &options).unwrap());
+        lsb::embed(&mut image, &payload, &options, None).unwrap();
+        png.replace_image(&image, &EncodeOptions::default())
+            .unwrap();
+
+        let report = ScanReport::new(&png).unwrap();
+        assert!(has_finding(&report, "chi-square"));
+        assert!(report.risk_score > clean.risk_score);
+    }
+
+    #[test]
+    fn test_rs_estimate() {
+        // Smooth gradient with a little texture, like a natural image
+        let width = 64;
+        let values: Vec<i32> = (0..width * 64)
+            .map(|i| ((i % width) * 2 + (i / width) + i * 7 % 5) as i32)
+            .collect();
+        let clean = rs_estimate(&values, width).unwrap();
+
+        let bits = noise(values.len());
+        let stego: Vec<i32> = values
+            .iter()
+            .zip(bits)
+            .map(|(&value, bit)| (value & !1) | (bit & 1) as i32)
+            .collect();
+        let rate = rs_estimate(&stego, width).unwrap();
+
+        assert!(rate > clean);
+        assert!(rate > RS_THRESHOLDS.1);
+    }
+
+    #[test]
+    fn test_to_json() {
+        let report = ScanReport::new(&Png::try_from(&PNG_FILE[..]).unwrap()).unwrap();
+        let json: serde_json::Value = serde_json::from_str(&report.to_json().unwrap()).unwrap();
+        assert_eq!(json["risk_score"], report.risk_score);
+        assert_eq!(json["findings"][0]["severity"], "high");
+    }
+}
@@ -0,0 +1,243 @@
+use std::collections::HashSet;
+use std::str::FromStr;
+
+use anyhow::{ensure, Context, Error, Result};
+use rand::Rng;
+
+use crate::chunk::Chunk;
+use crate::chunk_type::ChunkType;
+use crate::png::Png;
+
+/// Private, ancillary and safe to copy, so editors keep the share
+pub const CHUNK_TYPE: &str = "shRe";
+
+/// Size of the share-set identifier, the threshold and the share index before the share data
+const HEADER_SIZE: usize = 10;
+
+/// One share of a secret split with Shamir's secret sharing over GF(256)
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Share {
+    /// Random identifier that all shares of one split have in common
+    pub set_id: [u8; 8],
+    /// Number of shares needed to reconstruct the secret
+    pub threshold: u8,
+    /// Point at which the polynomials are evaluated, never zero
+    pub index: u8,
+    pub data: Vec<u8>,
+}
+
+impl Share {
+    pub fn as_chunk(&self) -> Chunk {
+        let data = self
+            .set_id
+            .iter()
+            .chain([self.threshold, self.index].iter())
+            .chain(self.data.iter())
+            .copied()
+            .collect();
+
+        Chunk::new(ChunkType::from_str(CHUNK_TYPE).unwrap(), data)
+    }
+}
+
+impl TryFrom<&Png> for Share {
+    type Error = Error;
+
+    fn try_from(png: &Png) -> Result<Self, Error> {
+        let data = &png
+            .chunk_by_type(CHUNK_TYPE)
+            .context("File holds no share")?
+            .data;
+        ensure!(data.len() >= HEADER_SIZE, "Share chunk is too short");
+        ensure!(data[8] != 0, "Share threshold cannot be zero");
+        ensure!(data[9] != 0, "Share index cannot be zero");
+
+        Ok(Share {
+            set_id: data[..8].try_into()?,
+            threshold: data[8],
+            index: data[9],
+            data: data[HEADER_SIZE..].to_vec(),
+        })
+    }
+}
+
+/// Multiplies in GF(256) with the AES polynomial x^8 + x^4 + x^3 + x + 1
+fn mul(mut a: u8, mut b: u8) -> u8 {
+    let mut product = 0;
+    while b != 0 {
+        if b & 1 != 0 {
+            product ^= a;
+        }
+        a = (a << 1) ^ if a & 0x80 != 0 { 0x1B } else { 0 };
+        b >>= 1;
+    }
+
+    product
+}
+
+/// Multiplicative inverse in GF(256), a^254
+fn inv(a: u8) -> u8 {
+    (0..7)
+        .fold((1, a), |(result, square), _| {
+            let square = mul(square, square);
+            (mul(result, square), square)
+        })
+        .0
+}
+
+/// Splits the secret into `count` shares, any `threshold` of which reconstruct it
+///
+/// Every byte of the secret is the constant term of its own random polynomial of degree
+/// threshold - 1, and share i holds the values of all polynomials at x = i.
+pub fn split(secret: &[u8], threshold: u8, count: u8) -> Result<Vec<Share>, Error> {
+    ensure!(threshold >= 1, "Threshold must be at least 1");
+    ensure!(
+        threshold <= count,
+        "Threshold cannot be larger than the number of shares"
+    );
+
+    let mut rng = rand::thread_rng();
+    let set_id: [u8; 8] = rng.gen();
+    let mut shares: Vec<Share> = (1..=count)
+        .map(|index| Share {
+            set_id,
+            threshold,
+            index,
+            data: Vec::with_capacity(secret.len()),
+        })
+        .collect();
+
+    for &byte in secret {
+        let coefficients: Vec<u8> = std::iter::once(byte)
+            .chain((1..threshold).map(|_| rng.gen()))
+            .collect();
+
+        for share in shares.iter_mut() {
+            // Horner's method from the highest coefficient down
+            let y = coefficients
+                .iter()
+                .rev()
+                .fold(0, |y, &coefficient| mul(y, share.index) ^ coefficient);
+            share.data.push(y);
+        }
+    }
+
+    Ok(shares)
+}
+
+/// Reconstructs the secret with Lagrange interpolation at x = 0
+pub fn combine(shares: &[Share]) -> Result<Vec<u8>, Error> {
+    let first = shares.first().context("No shares given")?;
+    ensure!(
+        shares.iter().all(|share| share.set_id == first.set_id),
+        "Shares belong to different share sets"
+    );
+    ensure!(
+        shares
+            .iter()
+            .all(|share| share.threshold == first.threshold && share.data.len() == first.data.len()),
+        "Shares of the same set disagree on the threshold or the secret size"
+    );
+
+    let mut indices = HashSet::new();
+    let shares: Vec<&Share> = shares
+        .iter()
+        .filter(|share| indices.insert(share.index))
+        .collect();
+    ensure!(
+        shares.len() >= first.threshold as usize,
+        "{} different shares given, but {} are needed",
+        shares.len(),
+        first.threshold
+    );
+    let shares = &shares[..first.threshold as usize];
+
+    // Lagrange basis polynomials at zero, subtraction is XOR in GF(256)
+    let weights: Vec<u8> = shares
+        .iter()
+        .map(|share| {
+            shares
+                .iter()
+                .filter(|other| other.index != share.index)
+                .fold(1, |weight, other| {
+                    mul(weight, mul(other.index, inv(other.index ^ share.index)))
+                })
+        })
+        .collect();
+
+    Ok((0..first.data.len())
+        .map(|i| {
+            shares
+                .iter()
+                .zip(&weights)
+                .fold(0, |secret, (share, &weight)| {
+                    secret ^ mul(share.data[i], weight)
+                })
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::png::tests::PNG_FILE;
+
+    use super::*;
+
+    #[test]
+    fn test_gf256() {
+        assert_eq!(mul(0x57, 0x83), 0xC1);
+        assert!((1..=255).all(|a| mul(a, inv(a)) == 1));
+    }
+
+    #[test]
+    fn test_split_combine() {
+        let secret = b"correct horse battery staple";
+        let shares = split(secret, 3, 5).unwrap();
+        assert_eq!(shares.len(), 5);
+
+        for picked in [[0, 1, 2], [4, 2, 0], [1, 3, 4]] {
+            let subset: Vec<Share> = picked.iter().map(|&i| shares[i].clone()).collect();
+            assert_eq!(combine(&subset).unwrap(), secret);
+        }
+    }
+
+    #[test]
+    fn test_too_few_shares() {
+        let shares = split(b"secret", 3, 5).unwrap();
+        let error = combine(&[shares[0].clone(), shares[3].clone(), shares[0].clone()]);
+        assert_eq!(
+            error.unwrap_err().to_string(),
+            "2 different shares given, but 3 are needed"
+        );
+    }
+
+    #[test]
+    fn test_mixed_sets() {
+        let a = split(b"secret", 2, 2).unwrap();
+        let b = split(b"secret", 2, 2).unwrap();
+        assert!(combine(&[a[0].clone(), b[1].clone()]).is_err());
+    }
+
+    #[test]
+    fn test_share_chunk() {
+        let share = split(b"secret", 2, 3).unwrap().remove(1);
+        let mut png = Png::try_from(&PNG_FILE[..]).unwrap();
+        png.append_chunk(share.as_chunk());
+
+        assert_eq!(Share::try_from(&png).unwrap(), share);
+
+        let zero_threshold = Share {
+            threshold: 0,
+            ..share
+        };
+        let mut png = Png::try_from(&PNG_FILE[..]).unwrap();
+        png.append_chunk(zero_threshold.as_chunk());
+        assert!(Share::try_from(&png).is_err());
+    }
+
+    #[test]
+    fn test_invalid_threshold() {
+        assert!(split(b"secret", 0, 3).is_err());
+        assert!(split(b"secret", 4, 3).is_err());
+    }
+}
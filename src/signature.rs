@@ -0,0 +1,319 @@
+use std::fmt;
+use std::fmt::{Display, Formatter};
+use std::fs;
+use std::path::Path;
+use std::str::FromStr;
+
+use anyhow::{anyhow, bail, ensure, Context, Error, Result};
+use ed25519_dalek::{Signature, Signer as _, SigningKey, Verifier as _, VerifyingKey};
+use rand::Rng;
+use sha2::{Digest, Sha256};
+
+use crate::chunk::Chunk;
+use crate::chunk_type::ChunkType;
+use crate::png::Png;
+use crate::util::{decode_key, encode_key, key_lines};
+
+/// Private, ancillary and safe to copy, like the chunks it signs
+pub const CHUNK_TYPE: &str = "siGn";
+
+const PUBLIC_KEY_HRP: &str = "pngmesig";
+const SECRET_KEY_HRP: &str = "PNGME-SIGNING-KEY-";
+
+/// Prefix of every signed message, so signatures cannot be reused for anything else
+const DOMAIN: &[u8] = b"pngme-signature";
+
+/// Signed chunk type, public key of the signer and the signature
+const DATA_SIZE: usize = 4 + 32 + 64;
+
+/// Private, ancillary and unsafe to copy, so editors that change critical chunks drop it
+pub const IMAGE_CHUNK_TYPE: &str = "imSG";
+
+/// Prefix of every signed image digest
+const IMAGE_DOMAIN: &[u8] = b"pngme-image-signature";
+
+/// Header digest, pixel digest, public key of the signer and the signature
+const IMAGE_DATA_SIZE: usize = 32 + 32 + 32 + 64;
+
+/// Public key that checks signatures, written as `pngmesig1...`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Verifier(VerifyingKey);
+
+/// Secret key that signs messages, written as `PNGME-SIGNING-KEY-1...`
+pub struct Signer(SigningKey);
+
+impl Signer {
+    pub fn generate() -> Signer {
+        Signer(SigningKey::from_bytes(&rand::thread_rng().gen()))
+    }
+
+    pub fn verifier(&self) -> Verifier {
+        Verifier(self.0.verifying_key())
+    }
+
+    /// Reads the signing key of a file, skipping blank lines and `#` comments
+    pub fn read_file(path: &Path) -> Result<Signer, Error> {
+        let text = fs::read_to_string(path)
+            .with_context(|| format!("Cannot read signing key file {}", path.display()))?;
+
+        let key = key_lines(&text)
+            .next()
+            .context("Signing key file holds no secret key")?;
+
+        Signer::from_str(key)
+    }
+
+    /// Contents of a signing key file, with the public key as a comment
+    pub fn to_file_string(&self) -> String {
+        format!("# public key: {}\n{}\n", self.verifier(), self)
+    }
+}
+
+impl FromStr for Verifier {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Error> {
+        Verifier::try_from(&decode_key(s, PUBLIC_KEY_HRP)?[..])
+    }
+}
+
+impl FromStr for Signer {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Error> {
+        Ok(Signer(SigningKey::from_bytes(&decode_key(
+            s,
+            SECRET_KEY_HRP,
+        )?)))
+    }
+}
+
+impl TryFrom<&[u8]> for Verifier {
+    type Error = Error;
+
+    fn try_from(bytes: &[u8]) -> Result<Self, Error> {
+        VerifyingKey::from_bytes(bytes.try_into()?)
+            .map(Verifier)
+            .map_err(|_| anyhow!("Public key is not a point on the curve"))
+    }
+}
+
+impl Display for Verifier {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}",
+            encode_key(PUBLIC_KEY_HRP, self.0.as_bytes(), false)
+        )
+    }
+}
+
+impl Display for Signer {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", encode_key(SECRET_KEY_HRP, self.0.as_bytes(), true))
+    }
+}
+
+fn signed_message(chunk: &Chunk) -> Vec<u8> {
+    [DOMAIN, &chunk.r#type.bytes(), &chunk.data].concat()
+}
+
+/// Signs the type and data of the chunk into a signature chunk
+pub fn sign(chunk: &Chunk, signer: &Signer) -> Chunk {
+    let signature = signer.0.sign(&signed_message(chunk));
+    let data = [
+        &chunk.r#type.bytes()[..],
+        signer.0.verifying_key().as_bytes(),
+        &signature.to_bytes(),
+    ]
+    .concat();
+
+    Chunk::new(ChunkType::from_str(CHUNK_TYPE).unwrap(), data)
+}
+
+/// Checks the signature of the chunk of the given type and returns who signed it
+///
+/// With a trusted key, only a signature by that key is accepted.
+pub fn verify(png: &Png, chunk_type: &str, trusted: Option<&Verifier>) -> Result<Verifier, Error> {
+    let chunk = png
+        .chunk_by_type(chunk_type)
+        .context("Chunk does not exist")?;
+    let signatures: Vec<&[u8]> = png
+        .chunks
+        .iter()
+        .filter(|signature| signature.r#type.to_string() == CHUNK_TYPE)
+        .map(|signature| signature.data.as_slice())
+        .filter(|data| data.len() == DATA_SIZE && data[..4] == chunk.r#type.bytes())
+        .collect();
+    ensure!(!signatures.is_empty(), "{} chunk is not signed", chunk_type);
+
+    for data in signatures {
+        let verifier = Verifier::try_from(&data[4..36])?;
+        if trusted.is_some_and(|trusted| *trusted != verifier) {
+            continue;
+        }
+        let signature = Signature::from_bytes(data[36..].try_into()?);
+        verifier
+            .0
+            .verify(&signed_message(chunk), &signature)
+            .map_err(|_| anyhow!("Signature by {} is invalid", verifier))?;
+
+        return Ok(verifier);
+    }
+
+    bail!("{} chunk is not signed by the given key", chunk_type)
+}
+
+/// SHA-256 digests of the IHDR and PLTE data and of the decoded pixels
+///
+/// The pixels are hashed after decompression, so re-encoding the image or splitting its IDAT
+/// chunks keeps the digest.
+fn image_digests(png: &Png) -> Result<([u8; 32], [u8; 32]), Error> {
+    let mut header = Sha256::new();
+    for chunk_type in ["IHDR", "PLTE"] {
+        if let Some(chunk) = png.chunk_by_type(chunk_type) {
+            header.update(chunk.r#type.bytes());
+            header.update((chunk.data.len() as u32).to_be_bytes());
+            header.update(&chunk.data);
+        }
+    }
+    let pixels = Sha256::digest(png.raw_image()?.data);
+
+    Ok((header.finalize().into(), pixels.into()))
+}
+
+/// Signs the header and pixels of the image into a signature chunk
+pub fn sign_image(png: &Png, signer: &Signer) -> Result<Chunk, Error> {
+    let (header, pixels) = image_digests(png)?;
+    let signature = signer.0.sign(&[IMAGE_DOMAIN, &header, &pixels].concat());
+    let data = [
+        &header[..],
+        &pixels,
+        signer.0.verifying_key().as_bytes(),
+        &signature.to_bytes(),
+    ]
+    .concat();
+
+    Ok(Chunk::new(
+        ChunkType::from_str(IMAGE_CHUNK_TYPE).unwrap(),
+        data,
+    ))
+}
+
+/// Checks the image signature and that neither the header nor the pixels changed since
+///
+/// With a trusted key, only a signature by that key is accepted.
+pub fn verify_image(png: &Png, trusted: Option<&Verifier>) -> Result<Verifier, Error> {
+    let data = &png
+        .chunk_by_type(IMAGE_CHUNK_TYPE)
+        .context("Image is not signed")?
+        .data;
+    ensure!(data.len() == IMAGE_DATA_SIZE, "Image signature is invalid");
+
+    let verifier = Verifier::try_from(&data[64..96])?;
+    if let Some(trusted) = trusted {
+        ensure!(
+            *trusted == verifier,
+            "Image is signed by {}, not by the given key",
+            verifier
+        );
+    }
+    let signature = Signature::from_bytes(data[96..].try_into()?);
+    verifier
+        .0
+        .verify(&[IMAGE_DOMAIN, &data[..64]].concat(), &signature)
+        .map_err(|_| anyhow!("Signature by {} is invalid", verifier))?;
+
+    let (header, pixels) = image_digests(png)?;
+    match (header[..] == data[..32], pixels[..] == data[32..64]) {
+        (true, true) => Ok(verifier),
+        (false, true) => bail!("Header changed since signing"),
+        (true, false) => bail!("Pixels changed since signing"),
+        (false, false) => bail!("Header and pixels changed since signing"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::image::EncodeOptions;
+    use crate::png::tests::PNG_FILE;
+
+    use super::*;
+
+    fn signed_png(signer: &Signer) -> Png {
+        let mut png = Png::try_from(&PNG_FILE[..]).unwrap();
+        let chunk = Chunk::new(
+            ChunkType::from_str("ruSt").unwrap(),
+            b"Signed message".to_vec(),
+        );
+        png.append_chunk(sign(&chunk, signer));
+        png.append_chunk(chunk);
+        png
+    }
+
+    #[test]
+    fn test_key_strings() {
+        let signer = Signer::generate();
+        let verifier = signer.verifier();
+
+        assert!(verifier.to_string().starts_with("pngmesig1"));
+        assert_eq!(Verifier::from_str(&verifier.to_string()).unwrap(), verifier);
+        let parsed = Signer::from_str(&signer.to_string()).unwrap();
+        assert_eq!(parsed.verifier(), verifier);
+    }
+
+    #[test]
+    fn test_sign_verify() {
+        let signer = Signer::generate();
+        let png = signed_png(&signer);
+
+        assert_eq!(verify(&png, "ruSt", None).unwrap(), signer.verifier());
+        assert_eq!(
+            verify(&png, "ruSt", Some(&signer.verifier())).unwrap(),
+            signer.verifier()
+        );
+        assert!(verify(&png, "ruSt", Some(&Signer::generate().verifier())).is_err());
+        assert!(verify(&png, "RuSt", None).is_err());
+    }
+
+    #[test]
+    fn test_sign_verify_image() {
+        let signer = Signer::generate();
+        let mut png = Png::try_from(&PNG_FILE[..]).unwrap();
+        png.append_chunk(sign_image(&png, &signer).unwrap());
+
+        // Ancillary chunks and the IDAT layout are not covered
+        png.append_chunk(Chunk::new(
+            ChunkType::from_str("tEXt").unwrap(),
+            b"Comment\0Added later".to_vec(),
+        ));
+        png.split_idat(100).unwrap();
+        assert_eq!(verify_image(&png, None).unwrap(), signer.verifier());
+        assert!(verify_image(&png, Some(&Signer::generate().verifier())).is_err());
+
+        let mut image = png.raw_image().unwrap();
+        image.data[0] ^= 1;
+        png.replace_image(&image, &EncodeOptions::default())
+            .unwrap();
+        assert_eq!(
+            verify_image(&png, None).unwrap_err().to_string(),
+            "Pixels changed since signing"
+        );
+    }
+
+    #[test]
+    fn test_tampered() {
+        let mut png = signed_png(&Signer::generate());
+        png.chunks
+            .retain(|chunk| chunk.r#type.to_string() != "ruSt");
+        png.append_chunk(Chunk::new(
+            ChunkType::from_str("ruSt").unwrap(),
+            b"Changed message".to_vec(),
+        ));
+
+        assert!(verify(&png, "ruSt", None)
+            .unwrap_err()
+            .to_string()
+            .ends_with("is invalid"));
+    }
+}
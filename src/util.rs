@@ -1,6 +1,37 @@
-use anyhow::Error;
+use anyhow::{anyhow, ensure, Error};
+use bech32::{Bech32, Hrp};
 
 /// Slices a &[u8] to &[u8; 4] starting from the first_idx
 pub fn slice_4_bytes(value: &[u8], first_idx: usize) -> Result<[u8; 4], Error> {
     Ok(<[u8; 4]>::try_from(&value[first_idx..first_idx + 4])?)
 }
+
+/// Writes a 32 byte key as Bech32 with the human-readable prefix, upper case for secret keys
+pub fn encode_key(hrp: &str, key: &[u8; 32], upper: bool) -> String {
+    let hrp = Hrp::parse(hrp).unwrap();
+    if upper {
+        bech32::encode_upper::<Bech32>(hrp, key).unwrap()
+    } else {
+        bech32::encode_lower::<Bech32>(hrp, key).unwrap()
+    }
+}
+
+/// Reads a 32 byte key written by [`encode_key`], checking its prefix and checksum
+pub fn decode_key(s: &str, hrp: &str) -> Result<[u8; 32], Error> {
+    let (found, data) = bech32::decode(s).map_err(|error| anyhow!("Invalid key: {}", error))?;
+    ensure!(
+        found.as_str().eq_ignore_ascii_case(hrp),
+        "Key does not start with {}1",
+        hrp
+    );
+
+    data.try_into()
+        .map_err(|_| anyhow!("Key must be 32 bytes long"))
+}
+
+/// Lines of a key file that hold keys, skipping blank lines and `#` comments
+pub fn key_lines(text: &str) -> impl Iterator<Item = &str> {
+    text.lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+}
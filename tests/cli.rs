@@ -0,0 +1,197 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output};
+
+use crc::{Crc, CRC_32_ISO_HDLC};
+
+/// 1x1 RGBA image with a single opaque red pixel
+const PNG_FILE: [u8; 70] = [
+    137, 80, 78, 71, 13, 10, 26, 10, 0, 0, 0, 13, 73, 72, 68, 82, 0, 0, 0, 1, 0, 0, 0, 1, 8, 6, 0,
+    0, 0, 31, 21, 196, 137, 0, 0, 0, 13, 73, 68, 65, 84, 120, 156, 99, 248, 207, 192, 240, 31, 0,
+    5, 0, 1, 255, 137, 153, 61, 29, 0, 0, 0, 0, 73, 69, 78, 68, 174, 66, 96, 130,
+];
+
+/// PNG_FILE with a chunk inserted before IEND
+fn with_chunk(chunk_type: &str, data: &[u8]) -> Vec<u8> {
+    let crc = Crc::<u32>::new(&CRC_32_ISO_HDLC);
+    let type_and_data = [chunk_type.as_bytes(), data].concat();
+    let iend = PNG_FILE.len() - 12;
+    [
+        &PNG_FILE[..iend],
+        &(data.len() as u32).to_be_bytes(),
+        &type_and_data,
+        &crc.checksum(&type_and_data).to_be_bytes(),
+        &PNG_FILE[iend..],
+    ]
+    .concat()
+}
+
+fn temp_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("pngme-cli-{}-{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// Runs pngme with the keyring kept under `config`
+fn pngme(config: &Path, args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_pngme"))
+        .env("XDG_CONFIG_HOME", config)
+        .args(args)
+        .output()
+        .unwrap()
+}
+
+fn succeeds(config: &Path, args: &[&str]) -> bool {
+    pngme(config, args).status.success()
+}
+
+/// Creates a signing key file and returns its public key
+fn keygen(config: &Path, path: &Path) -> String {
+    let output = pngme(config, &["keygen", "--signing", path.to_str().unwrap()]);
+    assert!(output.status.success());
+    String::from_utf8(output.stdout)
+        .unwrap()
+        .trim()
+        .strip_prefix("Public key: ")
+        .unwrap()
+        .to_string()
+}
+
+// The keyring lives in $XDG_CONFIG_HOME only on Linux
+#[cfg(target_os = "linux")]
+#[test]
+fn test_keyring_names_for_signatures() {
+    let dir = temp_dir("keyring");
+    let file = dir.join("image.png");
+    fs::write(&file, PNG_FILE).unwrap();
+    let file = file.to_str().unwrap();
+    let key = dir.join("alice.key");
+    let alice = keygen(&dir, &key);
+    let bob = keygen(&dir, &dir.join("bob.key"));
+
+    let key = key.to_str().unwrap();
+    assert!(succeeds(&dir, &["keys", "add", "alice", &alice]));
+    assert!(succeeds(&dir, &["keys", "add", "bob", &bob]));
+
+    assert!(succeeds(&dir, &["sign-image", file, "--key", key]));
+    assert!(succeeds(&dir, &["verify-image", file, "--from", "alice"]));
+    assert!(!succeeds(&dir, &["verify-image", file, "--from", "bob"]));
+    assert!(!succeeds(&dir, &["verify-image", file, "--from", "carol"]));
+    let both = ["verify-image", file, "--from", "alice", "--pubkey", &alice];
+    assert!(!succeeds(&dir, &both));
+
+    let message = ["encode", file, "ruSt", "Hello", "--sign", key];
+    assert!(succeeds(&dir, &message));
+    assert!(succeeds(&dir, &["verify", file, "ruSt", "--from", "alice"]));
+    assert!(!succeeds(&dir, &["verify", file, "ruSt", "--from", "bob"]));
+
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn test_share_split_again() {
+    let dir = temp_dir("share");
+    let files: Vec<String> = ["a.png", "b.png"]
+        .iter()
+        .map(|name| {
+            let file = dir.join(name);
+            fs::write(&file, PNG_FILE).unwrap();
+            file.to_str().unwrap().to_string()
+        })
+        .collect();
+    let secret = dir.join("secret");
+    let secret = secret.to_str().unwrap();
+
+    for text in ["first secret", "second secret"] {
+        fs::write(secret, text).unwrap();
+        let split = [
+            "share",
+            "split",
+            "--threshold",
+            "2",
+            "--shares",
+            "2",
+            secret,
+        ];
+        assert!(succeeds(
+            &dir,
+            &[&split[..], &[&files[0], &files[1]]].concat()
+        ));
+    }
+
+    let output = pngme(&dir, &["share", "combine", &files[0], &files[1]]);
+    assert!(output.status.success());
+    assert_eq!(output.stdout, b"second secret");
+
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn test_existing_trailer() {
+    let dir = temp_dir("trailer");
+    let file = dir.join("image.png");
+    let polyglot = [&PNG_FILE[..], b"PK\x03\x04\xff\xfe"].concat();
+    fs::write(&file, &polyglot).unwrap();
+    let file = file.to_str().unwrap();
+
+    let output = pngme(&dir, &["decode", file, "--method", "trailer"]);
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("No pngme message in trailer"));
+
+    assert!(!succeeds(
+        &dir,
+        &["encode", file, "--method", "trailer", "Hi"]
+    ));
+    assert_eq!(fs::read(file).unwrap(), polyglot);
+
+    let overwrite = [
+        "encode",
+        file,
+        "--method",
+        "trailer",
+        "Hi",
+        "--overwrite-trailer",
+    ];
+    assert!(succeeds(&dir, &overwrite));
+    let output = pngme(&dir, &["decode", file, "--method", "trailer"]);
+    assert_eq!(output.stdout, b"Hi\n");
+
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn test_raw_text_chunk() {
+    let dir = temp_dir("raw");
+    let file = dir.join("image.png");
+    fs::write(&file, with_chunk("ruSt", b"Hidden before envelopes")).unwrap();
+    let file = file.to_str().unwrap();
+
+    let output = pngme(&dir, &["decode", file, "ruSt"]);
+    assert!(output.status.success());
+    assert_eq!(output.stdout, b"Hidden before envelopes\n");
+    assert!(!succeeds(&dir, &["decode", file, "ruSt", "--decrypt"]));
+
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn test_fragment_without_fragmented() {
+    let dir = temp_dir("fragment");
+    let file = dir.join("image.png");
+    fs::write(&file, PNG_FILE).unwrap();
+    let file = file.to_str().unwrap();
+
+    let encode = ["encode", file, "ruSt", "Hello world", "--fragments", "2"];
+    assert!(succeeds(&dir, &encode));
+    let output = pngme(&dir, &["decode", file, "ruSt"]);
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("decode it with --fragmented"));
+
+    fs::write(file, with_chunk("ruSt", &[0xFF, 0xFE, 0xFD])).unwrap();
+    let output = pngme(&dir, &["decode", file, "ruSt"]);
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("Chunk holds no pngme message"));
+
+    fs::remove_dir_all(dir).unwrap();
+}